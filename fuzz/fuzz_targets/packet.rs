@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2p::{peer::decode_config, types::Packet};
+
+// Anyone can send us anything, so decoding must never panic or allocate
+// more than a frame, and whatever decodes must survive a round trip.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((packet, _)) = bincode::decode_from_slice::<Packet, _>(data, decode_config()) {
+        let bytes = bincode::encode_to_vec(&packet, bincode::config::standard()).unwrap();
+        let (decoded, _): (Packet, _) = bincode::decode_from_slice(&bytes, decode_config()).unwrap();
+        assert_eq!(decoded, packet);
+    }
+});
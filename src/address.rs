@@ -0,0 +1,157 @@
+use std::{fmt, str::FromStr};
+
+use base64ct::{Base64, Encoding};
+use bech32::{primitives::decode::{CheckedHrpstring, CheckedHrpstringError}, Bech32m, Hrp};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::types::*;
+
+/// The human readable prefix of every account address.
+pub const ADDRESS_HRP: Hrp = Hrp::parse_unchecked("dkk");
+
+/// The human readable prefix of every asset id, except DKK which is written as is.
+pub const ASSET_HRP: Hrp = Hrp::parse_unchecked("asset");
+
+#[derive(Debug)]
+pub enum AddressError {
+    /// The address is neither valid bech32 nor legacy base64.
+    Malformed(String),
+    /// The address is bech32, but the checksum or encoding is wrong. Only
+    /// bech32m checksums are accepted.
+    Bech32(CheckedHrpstringError),
+    /// The address is bech32m, but has the first prefix rather than the second.
+    WrongPrefix(String, Hrp),
+    /// The address decodes to the wrong number of bytes.
+    InvalidLength(usize),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Malformed(s) => write!(f, "'{}' is not an address", s),
+            AddressError::Bech32(e) => write!(f, "Invalid address: {}", e),
+            AddressError::WrongPrefix(hrp, expected) => write!(f, "Address has prefix '{}', expected '{}'", hrp, expected),
+            AddressError::InvalidLength(len) => write!(f, "Address is {} bytes long, expected 32", len),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Decodes a bech32m string with the prefix `expected`.
+fn decode(s: &str, expected: Hrp) -> Result<Vec<u8>, AddressError> {
+    let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(AddressError::Bech32)?;
+    if checked.hrp() != expected {
+        return Err(AddressError::WrongPrefix(checked.hrp().to_string(), expected))
+    }
+
+    Ok(checked.byte_iter().collect())
+}
+
+impl Id {
+    // The bytes aren't checked to be a valid public key, since multisig ids are hashes.
+    fn from_bytes(bytes: Vec<u8>) -> Result<Id, AddressError> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|v: Vec<u8>| AddressError::InvalidLength(v.len()))?;
+
+        Ok(bytes.into())
+    }
+}
+
+/// Parses a bech32m address with the `dkk` prefix. Legacy base64 encoded
+/// public keys, optionally quoted, are accepted too.
+impl FromStr for Id {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        // The separator is the last '1', so a string without one can't be bech32.
+        if s.contains('1') {
+            match decode(s, ADDRESS_HRP) {
+                Ok(bytes) => return Id::from_bytes(bytes),
+                Err(e @ AddressError::WrongPrefix(..)) => return Err(e),
+                Err(e) if s.to_lowercase().starts_with("dkk1") => return Err(e),
+                Err(_) => (),
+            }
+        }
+
+        match Base64::decode_vec(s.trim_matches('"')) {
+            Ok(bytes) => Id::from_bytes(bytes),
+            Err(_) => Err(AddressError::Malformed(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, ADDRESS_HRP, self.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Id::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// Parses `DKK`, or a bech32m asset id with the `asset` prefix.
+impl FromStr for AssetId {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("DKK") {
+            return Ok(AssetId::DKK)
+        }
+
+        let bytes = decode(s, ASSET_HRP)?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|v: Vec<u8>| AddressError::InvalidLength(v.len()))?;
+
+        Ok(bytes.into())
+    }
+}
+
+impl fmt::Display for AssetId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == AssetId::DKK {
+            return write!(f, "DKK")
+        }
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, ASSET_HRP, self.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for AssetId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for AssetId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AssetId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<AssetId, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        AssetId::from_str(&s).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,178 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use anyhow::anyhow;
+
+use crate::{client::*, genesis::*, hd, merkle::Hash, types::*};
+
+/// How often the ledgers are checked for confirmed transactions.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Connecting is followed by a peer exchange, which should settle before
+/// the clock starts.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub nodes: usize,
+    pub trxs: usize,
+    /// The fractions of nodes to run with `behaviour` rather than honestly.
+    pub byzantine: Vec<f64>,
+    pub behaviour: Behaviour,
+    /// Transactions not confirmed by every node within this long are dropped
+    /// from the latencies, and counted in the `unconfirmed` column.
+    pub timeout: Duration,
+}
+
+impl Default for BenchConfig {
+    fn default() -> BenchConfig {
+        BenchConfig {
+            nodes: 4,
+            trxs: 1000,
+            byzantine: vec![0.0],
+            behaviour: Behaviour::Withholding,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct BenchResult {
+    pub byzantine: usize,
+    pub confirmed: usize,
+    /// Honest nodes whose ledger differs from that of most honest nodes,
+    /// once the run has settled.
+    pub diverged: usize,
+    pub elapsed: Duration,
+    /// Sorted, from submission until every honest node has applied the transaction.
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchResult {
+    pub const CSV_HEADER: &'static str = "byzantine_nodes,trxs,unconfirmed,diverged_nodes,tps,p50_ms,p90_ms,p99_ms,max_ms";
+
+    pub fn tps(&self) -> f64 {
+        match self.confirmed {
+            0 => 0.0,
+            confirmed => confirmed as f64 / self.elapsed.as_secs_f64(),
+        }
+    }
+
+    pub fn percentile(&self, q: f64) -> Duration {
+        match self.latencies.len() {
+            0 => Duration::ZERO,
+            len => self.latencies[((len - 1) as f64 * q).round() as usize],
+        }
+    }
+
+    pub fn to_csv(&self, trxs: usize) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        format!(
+            "{},{},{},{},{:.1},{:.1},{:.1},{:.1},{:.1}",
+            self.byzantine, trxs, trxs - self.confirmed, self.diverged, self.tps(),
+            ms(self.percentile(0.5)), ms(self.percentile(0.9)), ms(self.percentile(0.99)), ms(self.percentile(1.0)),
+        )
+    }
+}
+
+/// Runs once for every fraction of byzantine nodes, printing a CSV line for
+/// each as it finishes.
+pub async fn run(config: &BenchConfig) -> anyhow::Result<Vec<BenchResult>> {
+    println!("{}", BenchResult::CSV_HEADER);
+
+    let mut results = vec![];
+    for &byzantine in &config.byzantine {
+        let byzantine = (config.nodes as f64 * byzantine).round() as usize;
+        let result = run_once(config, byzantine).await?;
+        println!("{}", result.to_csv(config.trxs));
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+/// Spins up `config.nodes` nodes on a fresh testnet, the last `byzantine` of
+/// which behave as `config.behaviour`, submits `config.trxs` transfers spread
+/// over all of them, and times until every honest node applied them.
+/// `Behaviour::Equivocating` nodes hold the keys of the transfers they
+/// submit, so they can sign conflicting versions of them.
+pub async fn run_once(config: &BenchConfig, byzantine: usize) -> anyhow::Result<BenchResult> {
+    if byzantine >= config.nodes {
+        return Err(anyhow!("The benchmark needs at least one honest node"))
+    }
+
+    let node_config = NodeConfig { genesis: Genesis::testnet()?, ..Default::default() };
+
+    let mut nodes = vec![];
+    for i in 0..config.nodes {
+        let behaviour = if i < config.nodes - byzantine { Behaviour::Honest } else { config.behaviour };
+        let node_config = NodeConfig { behaviour, ..node_config.clone() };
+        nodes.push(Node::with_config(&format!("Bench{}", i), node_config).await?);
+    }
+    let honest = &nodes[..config.nodes - byzantine];
+    for node in &nodes[1..] {
+        node.connect(nodes[0].get_address()).await?;
+    }
+    tokio::time::sleep(SETTLE_TIME).await;
+
+    // Sign everything up front, so signing isn't part of the measurement.
+    // Each transfer goes to a fresh account, which makes it easy to spot.
+    let senders = hd::genesis_accounts(10)?;
+    let mut trxs = vec![];
+    for i in 0..config.trxs {
+        let from = &senders[i % senders.len()];
+        let trx = AccountTransaction {
+            from: from.public,
+            to: nodes[0].gen_keys().public,
+            asset: AssetId::DKK,
+            amount: Amount(1),
+            fee: Amount(0),
+            lock: None,
+            outputs: vec![],
+            valid_after: None,
+            valid_until: None,
+            timestamp: Timestamp::since_unix()?,
+        };
+        trxs.push(from.private.sign(trx)?);
+    }
+
+    let start = Instant::now();
+    let mut pending = HashMap::new();
+    for (i, trx) in trxs.into_iter().enumerate() {
+        pending.insert(trx.trx.to, Instant::now());
+        let node = &nodes[i % nodes.len()];
+        if node.config.behaviour == Behaviour::Equivocating {
+            node.use_keys(senders[i % senders.len()].clone());
+        }
+        node.send(trx).await;
+    }
+
+    let mut latencies = vec![];
+    let mut last_confirmed = start;
+    while !pending.is_empty() && start.elapsed() < config.timeout {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let now = Instant::now();
+        pending.retain(|to, submitted| {
+            let confirmed = honest.iter().all(|node| node.get_ledger().get(to).is_some());
+            if confirmed {
+                latencies.push(now - *submitted);
+                last_confirmed = now;
+            }
+            !confirmed
+        });
+    }
+    latencies.sort();
+
+    tokio::time::sleep(SETTLE_TIME).await;
+    let mut roots: HashMap<Hash, usize> = HashMap::new();
+    for node in honest {
+        *roots.entry(node.get_ledger().state_root()).or_default() += 1;
+    }
+    let agreeing = roots.into_values().max().unwrap_or_default();
+
+    Ok(BenchResult {
+        byzantine,
+        confirmed: latencies.len(),
+        diverged: honest.len() - agreeing,
+        elapsed: last_confirmed - start,
+        latencies,
+    })
+}
@@ -1,36 +1,220 @@
+use std::collections::HashMap;
 use std::net::{SocketAddr, Ipv4Addr, IpAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use rand::Rng;
-use tokio::{net::TcpListener, sync::mpsc::{Sender, channel, Receiver}};
+use anyhow::anyhow;
+
+use dashmap::{DashMap, DashSet};
+use rand::{Rng, seq::SliceRandom};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, sync::{broadcast, oneshot, mpsc::{Sender, channel, Receiver}}};
 use rand::rngs::OsRng;
 use ed25519_dalek::SigningKey;
 
 use crate::types::*;
-use crate::macros::*;
+use crate::genesis::Genesis;
+use crate::{log_fail, skip_fail};
+use crate::merkle::Hash;
+use crate::metrics::{self, Metrics, Rejection};
+use crate::peer::Peer;
+
+/// How often the state root is announced to peers.
+const STATE_ROOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait for a peer to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many received chat messages are buffered for slow subscribers.
+const CHAT_BUFFER: usize = 100;
+
+/// How long chat message ids are remembered, and how many at most. Messages
+/// are flooded within seconds, so one seen again after this is old news.
+const CHAT_SEEN_EXPIRY: Duration = Duration::from_secs(10 * 60);
+const MAX_CHAT_SEEN: usize = 10_000;
+
+/// How often a `Behaviour::Spammer` sends an invalid transaction to each peer.
+const SPAM_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How far our clock may be from our peers', bounding how far from it an
+/// escrow release may be timestamped, or a relayed transaction may expire.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(5);
+
+/// How long transfers signed by the node stay valid.
+const TRANSFER_VALIDITY: Duration = Duration::from_secs(60 * 60);
+
+/// How often expired transactions are evicted from the history and the signature cache.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long, and for how many transactions, verified signatures are cached
+/// at most. Forgetting one only means verifying it again.
+const VERIFIED_EXPIRY: Duration = Duration::from_secs(10 * 60);
+const MAX_VERIFIED: usize = 100_000;
+
+/// How many queued up transactions from peers are verified together.
+const MAX_VERIFY_BATCH: usize = 64;
+
+/// How many releases of escrows we haven't seen locked yet are held back,
+/// in all and from each peer. Releases aren't signed, so anyone can send them.
+const MAX_PENDING_RELEASES: usize = 1000;
+const MAX_PENDING_RELEASES_PER_PEER: usize = 100;
+
+/// The name, help text, kind and value of a metric reported for each peer.
+type PeerMetric = (&'static str, &'static str, &'static str, fn(&Peer) -> u64);
+
+#[derive(Clone, Debug, Default)]
+pub struct NodeConfig {
+    pub genesis: Genesis,
+    /// Light nodes keep no ledger or history, and instead ask their peers
+    /// for proofs of the balances they are interested in.
+    pub light: bool,
+    /// Transactions paying less per output are rejected, rather than applied
+    /// and not relayed, which would leave peers with another ledger. This is
+    /// also the fee paid per output by `transfer`. Nodes with different
+    /// minimums still end up with different ledgers, as the stricter ones
+    /// reject what the others apply, so every node should use the same.
+    pub min_relay_fee: Amount,
+    /// Chat messages, including our own, are forwarded to each peer after a
+    /// random delay of up to this long, so the first peer to forward a
+    /// message isn't obviously its author. Zero forwards immediately.
+    pub chat_delay: Duration,
+    /// Propagate transactions with Dandelion++, `None` floods them right away.
+    pub dandelion: Option<DandelionConfig>,
+    /// Serve Prometheus metrics on `http://<addr>/metrics`.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Anything but `Behaviour::Honest` is only meant for adversarial testing.
+    pub behaviour: Behaviour,
+}
+
+/// How a node treats the network. Deviating from `Honest` lets the tests
+/// and the simulator check how honest nodes cope with a fraction of bad peers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Behaviour {
+    #[default]
+    Honest,
+    /// Ignores every packet and announces nothing, as if it had crashed
+    /// without closing its connections.
+    Silent,
+    /// Applies transactions, but never relays them.
+    Withholding,
+    /// Floods its peers with badly signed transactions.
+    Spammer,
+    /// Answers balance proof requests with proofs from a forged ledger,
+    /// crediting the queried account with a million DKK.
+    Lying,
+    /// Sends each peer its own version of the transactions it submits from
+    /// the selected account, all signed, spending the same funds several times.
+    Equivocating,
+}
 
+impl FromStr for Behaviour {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "honest" => Ok(Behaviour::Honest),
+            "silent" => Ok(Behaviour::Silent),
+            "withholding" => Ok(Behaviour::Withholding),
+            "spammer" => Ok(Behaviour::Spammer),
+            "lying" => Ok(Behaviour::Lying),
+            "equivocating" => Ok(Behaviour::Equivocating),
+            _ => Err(anyhow!("Unknown behaviour '{}', expected honest, silent, withholding, spammer, lying or equivocating", s)),
+        }
+    }
+}
+
+/// How our state differs from a peer's, see `Node::compare`.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub accounts: Vec<AccountDiff>,
+    /// The first transaction that differs when ordering both histories by
+    /// timestamp, ours and then theirs. `None` on the side that ran out.
+    pub first_difference: Option<(Option<AccountTransaction>, Option<AccountTransaction>)>,
+}
+
+impl Divergence {
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.first_difference.is_none()
+    }
+}
+
+/// Dandelion++ hides who sent a transaction by first passing it along a
+/// random line of peers, the stem, before it is flooded, or fluffed.
+#[derive(Clone, Debug)]
+pub struct DandelionConfig {
+    /// The chance of fluffing a stem transaction received from a peer,
+    /// rather than passing it on. Our own transactions are always stemmed.
+    pub fluff_probability: f64,
+    /// How long stem transactions are passed to the same peer.
+    pub epoch: Duration,
+    /// If a stem transaction hasn't been fluffed after this long, we fluff it
+    /// ourselves, in case a node further down the stem dropped it.
+    pub embargo: Duration,
+}
+
+impl Default for DandelionConfig {
+    fn default() -> DandelionConfig {
+        DandelionConfig {
+            fluff_probability: 0.1,
+            epoch: Duration::from_secs(600),
+            embargo: Duration::from_secs(30),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Node {
     pub name: NodeName,
     pub socket: SocketAddr,
+    pub config: NodeConfig,
     node_tx: Sender<NodeRequest>,
+    wallet: Arc<RwLock<Option<KeyPair>>>,
+    /// Requests sent to peers awaiting a response, by nonce.
+    pending: Arc<DashMap<u64, oneshot::Sender<Packet>>>,
+    /// The ids of chat messages already seen and when, and the subscribers to new ones.
+    chat_seen: Arc<DashMap<[u8; 16], Instant>>,
+    chat_tx: broadcast::Sender<ChatMessage>,
+    /// The stem peer of the current epoch and when it was picked.
+    stem_peer: Arc<RwLock<Option<(Instant, SocketAddr)>>>,
+    /// Transactions we have passed along the stem, but not seen fluffed yet.
+    stempool: Arc<DashSet<TxId>>,
+    /// Releases that arrived before the transaction locking their escrow,
+    /// with when and from whom they were received.
+    pub(crate) pending_releases: Arc<DashMap<Id, (EscrowRelease, Timestamp, SocketAddr)>>,
+    pub metrics: Arc<Metrics>,
     pub state: State
 }
 
 impl Node {
     pub async fn new(name: &str) -> anyhow::Result<Self> {
+        Self::with_config(name, NodeConfig::default()).await
+    }
+
+    pub async fn with_config(name: &str, config: NodeConfig) -> anyhow::Result<Self> {
+        config.genesis.validate()?;
+
         // Get IP and port
         let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
         let port = rand::thread_rng().gen_range(20000..60000);
         let socket = SocketAddr::new(ip, port);
         let name = NodeName(name.to_owned());
         let (node_tx, node_rx) = channel::<NodeRequest>(1000);
+        let features = if config.light { Features::LIGHT } else { Features::default() };
 
         let node = Self {
-            state: State::new(socket, name.clone()),
+            state: State::new(socket, name.clone(), &config.genesis, features),
             name,
             socket,
+            config,
             node_tx,
+            wallet: Arc::new(RwLock::new(None)),
+            pending: Arc::new(DashMap::new()),
+            chat_seen: Arc::new(DashMap::new()),
+            chat_tx: broadcast::channel(CHAT_BUFFER).0,
+            stem_peer: Arc::new(RwLock::new(None)),
+            stempool: Arc::new(DashSet::new()),
+            pending_releases: Arc::new(DashMap::new()),
+            metrics: Arc::new(Metrics::default()),
         };
 
         log_fail!(node.listen().await);
@@ -40,6 +224,33 @@ impl Node {
             async move { node.peer_receiver(node_rx).await; }
         });
 
+        for addr in node.config.genesis.bootstrap_peers.clone() {
+            log_fail!(node.connect(addr).await);
+        }
+
+        if !node.config.light && node.config.behaviour != Behaviour::Silent {
+            tokio::spawn({
+                let node = node.clone();
+                async move { node.announce_state_root().await; }
+            });
+        }
+
+        tokio::spawn({
+            let node = node.clone();
+            async move { node.evict_expired().await; }
+        });
+
+        if node.config.behaviour == Behaviour::Spammer {
+            tokio::spawn({
+                let node = node.clone();
+                async move { node.spam().await; }
+            });
+        }
+
+        if let Some(addr) = node.config.metrics_addr {
+            node.serve_metrics(addr).await?;
+        }
+
         Ok(node)
     }
 
@@ -55,6 +266,22 @@ impl Node {
         self.state.history.clone()
     }
 
+    /// The transactions of an account matching `filter`, oldest first.
+    pub fn get_account_history(&self, id: &Id, filter: &HistoryFilter) -> Vec<AccountTransaction> {
+        self.state.history.query(id, filter)
+    }
+
+    /// The transactions of an account matching `filter`, oldest first, each
+    /// with the balance of the account in its asset right after it. Light
+    /// nodes don't keep balances, so theirs are `None`.
+    pub fn get_balance_history(&self, id: &Id, filter: &HistoryFilter) -> Vec<(AccountTransaction, Option<Amount>)> {
+        let light = self.config.light;
+        self.state.history.query_balances(id, filter, |asset| match light {
+            true => None,
+            false => Some(self.state.ledger.get_asset(id, asset).unwrap_or_default()),
+        })
+    }
+
     pub fn get_peers(&self) -> Peers {
         self.state.peers.clone()
     }
@@ -63,10 +290,20 @@ impl Node {
         self.state.ledger.clone()
     }
 
+    /// Checks that the ledger still holds exactly the money created in the genesis.
+    pub fn check_supply(&self) -> anyhow::Result<()> {
+        let supply = self.config.genesis.total_supply().ok_or_else(|| anyhow!("Genesis balances overflow"))?;
+        self.state.ledger.check_supply(supply)
+    }
+
     pub fn get_balance(&self, id: &Id) -> Amount {
         self.state.ledger.get(id).unwrap()
     }
 
+    pub fn get_asset_balance(&self, id: &Id, asset: &AssetId) -> Amount {
+        self.state.ledger.get_asset(id, asset).unwrap_or_default()
+    }
+
     pub async fn listen(&self) -> anyhow::Result<()> {
         let listener = TcpListener::bind(self.socket).await?;
 
@@ -78,7 +315,11 @@ impl Node {
                     let (stream, addr) = skip_fail!(listener.accept().await);
 
                     info!("󰟅 Listener accepted tcp stream from {:?}, handling:", addr);
-                    skip_fail!(node.state.peers.new_stream(node.node_tx.clone(), stream).await);
+                    // Handshakes can be slow, so don't hold up accepting other connections.
+                    tokio::spawn({
+                        let node = node.clone();
+                        async move { log_fail!(node.state.peers.new_stream(node.node_tx.clone(), stream).await) }
+                    });
                 }
             }
         });
@@ -88,9 +329,18 @@ impl Node {
 
     pub async fn peer_receiver(&self, mut rx: Receiver<NodeRequest>) {
         let node = self.clone();
+        // A packet taken off the queue while collecting a batch of transactions.
+        let mut next: Option<NodeRequest> = None;
         loop {
-            if let Some((packet, peer)) = rx.recv().await {
+            let request = match next.take() {
+                Some(request) => Some(request),
+                None => rx.recv().await,
+            };
+            if let Some((packet, peer)) = request {
                 debug!("{:?}: Received {:?} from {:?}", node.name, packet, peer);
+                if node.config.behaviour == Behaviour::Silent {
+                    continue;
+                }
                 match packet {
                     Packet::GetPeers => {
                         let peers = node.state.peers.to_vec();
@@ -99,7 +349,24 @@ impl Node {
                         peer.send(packet).await
                     }
                     Packet::Broadcast(trx) => {
-                        node.broadcast(trx);
+                        // Verify the transactions that are already queued up in one go.
+                        let mut trxs = vec![trx];
+                        while trxs.len() < MAX_VERIFY_BATCH {
+                            match rx.try_recv() {
+                                Ok((Packet::Broadcast(trx), _)) => trxs.push(trx),
+                                Ok(request) => {
+                                    next = Some(request);
+                                    break
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                        node.metrics.trxs_received.add(trxs.len() as u64);
+                        if node.config.light {
+                            trace!("{:?}: Light node ignoring {:?}", node.name, trxs);
+                            continue;
+                        }
+                        node.receive_batch(trxs);
                     }
                     Packet::AddPeer(socket) => {
                         skip_fail!(self.state.peers.add_peer(socket, peer))
@@ -107,6 +374,78 @@ impl Node {
                     Packet::ResponseGetPeers(peers) => {
                         skip_fail!(node.state.peers.new_conns(node.node_tx.clone(), peers).await);
                     }
+                    Packet::StateRoot { history, state } => {
+                        node.compare_state_root(&peer, history, state);
+                    }
+                    Packet::GetProof { nonce, query } => {
+                        if node.config.light {
+                            trace!("{:?}: Light node can't prove {:?}", node.name, query);
+                            continue;
+                        }
+                        let proof = match query {
+                            ProofQuery::Balance(id) if node.config.behaviour == Behaviour::Lying => {
+                                let forged = Ledger::from(node.state.ledger.snapshot());
+                                forged.add(&id, Amount(1_000_000));
+                                Proof::Balance(forged.prove_balance(&id))
+                            }
+                            ProofQuery::Balance(id) => Proof::Balance(node.state.ledger.prove_balance(&id)),
+                            ProofQuery::Transaction(id) => Proof::Transaction(node.state.history.prove_tx(&id)),
+                        };
+
+                        peer.send(Packet::ResponseProof { nonce, proof }).await
+                    }
+                    packet @ Packet::ResponseProof { nonce, .. } => {
+                        node.respond(nonce, packet);
+                    }
+                    Packet::GetState { nonce } => {
+                        if node.config.light {
+                            trace!("{:?}: Light node has no state to send", node.name);
+                            continue;
+                        }
+                        let ledger = node.state.ledger.snapshot();
+                        let history = node.state.history.ordered();
+
+                        peer.send(Packet::ResponseState { nonce, ledger, history }).await
+                    }
+                    packet @ Packet::ResponseState { nonce, .. } => {
+                        node.respond(nonce, packet);
+                    }
+                    Packet::Chat(msg) => {
+                        node.relay_chat(msg);
+                    }
+                    Packet::Stem(trx) => {
+                        node.metrics.trxs_received.inc();
+                        if node.config.light {
+                            trace!("{:?}: Light node ignoring stem {:?}", node.name, trx);
+                            continue;
+                        }
+                        // Nodes not running Dandelion++ fluff right away.
+                        match &node.config.dandelion {
+                            Some(d) if !rand::thread_rng().gen_bool(d.fluff_probability) => node.stem(trx),
+                            _ => node.broadcast(trx),
+                        }
+                    }
+                    Packet::Register(creation) => {
+                        if node.config.light {
+                            trace!("{:?}: Light node ignoring {:?}", node.name, creation);
+                            continue;
+                        }
+                        node.register(creation);
+                    }
+                    Packet::Issue(issuance) => {
+                        if node.config.light {
+                            trace!("{:?}: Light node ignoring {:?}", node.name, issuance);
+                            continue;
+                        }
+                        node.issue(issuance);
+                    }
+                    Packet::Release(release) => {
+                        if node.config.light {
+                            trace!("{:?}: Light node ignoring {:?}", node.name, release);
+                            continue;
+                        }
+                        node.receive_release(release, peer.get_address());
+                    }
                 }
             } else {
                 info!("peer_reveiver received empty request, shutting down");
@@ -115,6 +454,138 @@ impl Node {
         }
     }
 
+    /// Asks a connected peer for a balance or transaction proof. The proof
+    /// still has to be verified against a trusted root.
+    pub async fn request_proof(&self, addr: SocketAddr, query: ProofQuery) -> anyhow::Result<Proof> {
+        match self.request(addr, |nonce| Packet::GetProof { nonce, query }).await? {
+            Packet::ResponseProof { proof, .. } => Ok(proof),
+            packet => Err(anyhow!("Unexpected response {:?} from {}", packet, addr)),
+        }
+    }
+
+    /// Fetches the ledger and history of a peer and compares them to ours.
+    pub async fn compare(&self, addr: SocketAddr) -> anyhow::Result<Divergence> {
+        let (ledger, history) = match self.request(addr, |nonce| Packet::GetState { nonce }).await? {
+            Packet::ResponseState { ledger, history, .. } => (ledger, history),
+            packet => return Err(anyhow!("Unexpected response {:?} from {}", packet, addr)),
+        };
+
+        let ours = self.state.history.ordered();
+        let first_difference = (0..ours.len().max(history.len()))
+            .map(|i| (ours.get(i).cloned(), history.get(i).cloned()))
+            .find(|(ours, theirs)| ours != theirs);
+
+        Ok(Divergence {
+            accounts: self.state.ledger.snapshot().diff(&ledger),
+            first_difference,
+        })
+    }
+
+    /// Asks every peer for a proof of the balance of `id`, and returns the
+    /// balance proven against the state root that a majority of peers agree on.
+    pub async fn verified_balance(&self, id: &Id) -> anyhow::Result<Amount> {
+        let peers: Vec<SocketAddr> = self.state.peers
+            .iter()
+            .filter(|p| !p.value().get_features().contains(Features::LIGHT))
+            .map(|p| *p.key())
+            .collect();
+        let requests = peers.iter().map(|addr| self.request_proof(*addr, ProofQuery::Balance(*id)));
+        let responses = futures::future::join_all(requests).await;
+
+        let mut votes: HashMap<Hash, (usize, Amount)> = HashMap::new();
+        for response in responses {
+            match response {
+                Ok(Proof::Balance(proof)) => match proof.verify(&proof.state_root, id) {
+                    Some(amount) => votes.entry(proof.state_root).or_insert((0, amount)).0 += 1,
+                    None => warn!("{:?}: Received an invalid balance proof for {}", self.name, id),
+                },
+                Ok(proof) => warn!("{:?}: Expected a balance proof, got {:?}", self.name, proof),
+                Err(e) => debug!("{:?}: {}", self.name, e),
+            }
+        }
+
+        match votes.into_values().max_by_key(|(count, _)| *count) {
+            Some((count, amount)) if count * 2 > peers.len() => Ok(amount),
+            _ => Err(anyhow!("No majority of the {} peers agree on the balance of {}", peers.len(), id)),
+        }
+    }
+
+    async fn request(&self, addr: SocketAddr, packet: impl FnOnce(u64) -> Packet) -> anyhow::Result<Packet> {
+        let peer = self.state.peers.get(&addr).ok_or_else(|| anyhow!("Not connected to {}", addr))?;
+        let nonce = rand::random();
+        let (tx, rx) = oneshot::channel();
+
+        self.pending.insert(nonce, tx);
+        peer.send(packet(nonce)).await;
+        let res = tokio::time::timeout(REQUEST_TIMEOUT, rx).await;
+        self.pending.remove(&nonce);
+
+        Ok(res.map_err(|_| anyhow!("Request to {} timed out", addr))??)
+    }
+
+    fn respond(&self, nonce: u64, packet: Packet) {
+        match self.pending.remove(&nonce) {
+            Some((_, tx)) => { let _ = tx.send(packet); }
+            None => debug!("{:?}: Dropping unsolicited response {:?}", self.name, packet),
+        }
+    }
+
+    async fn announce_state_root(&self) {
+        let mut interval = tokio::time::interval(STATE_ROOT_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let packet = Packet::StateRoot {
+                history: self.state.history.root(),
+                state: self.state.ledger.state_root(),
+            };
+            for peer in self.state.peers.clone_iter() {
+                peer.1.send(packet.clone()).await;
+            }
+        }
+    }
+
+    /// Transactions expired by more than `MAX_CLOCK_DRIFT` can't be admitted
+    /// again, so they are dropped from the history, and there is no point in
+    /// remembering their signatures. The rest are only remembered for
+    /// `VERIFIED_EXPIRY`. Held back releases whose lock hasn't arrived by
+    /// now, and old chat message ids, are dropped too.
+    async fn evict_expired(&self) {
+        let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let now = skip_fail!(Timestamp::since_unix());
+            self.state.verified.retain(|_, until| *until >= now);
+            let admissible_since = Timestamp::from_millis(now.as_millis().saturating_sub(MAX_CLOCK_DRIFT.as_millis() as u64));
+            let evicted = self.state.history.evict_expired(admissible_since);
+            if evicted > 0 {
+                debug!("{:?}: Evicted {} expired transactions from the history", self.name, evicted);
+            }
+            let held_since = now.as_millis().saturating_sub(EVICTION_INTERVAL.as_millis() as u64);
+            self.pending_releases.retain(|_, (_, received, _)| received.as_millis() >= held_since);
+            self.chat_seen.retain(|_, seen| seen.elapsed() < CHAT_SEEN_EXPIRY);
+        }
+    }
+
+    fn compare_state_root(&self, peer: &Peer, history: Hash, state: Hash) {
+        let our_history = self.state.history.root();
+        let our_state = self.state.ledger.state_root();
+
+        if state == our_state {
+            trace!("{:?}: In sync with {:?} at {:?}", self.name, peer, state);
+        } else if history == our_history {
+            // Same transactions, but different balances. Releases, registrations and
+            // issuances aren't in the history, so we may just not have the same ones yet.
+            warn!(
+                "{:?}: State root {:?} != {:?} of {:?} with identical histories {:?}, see ':compare'",
+                self.name, our_state, state, peer, history
+            );
+        } else {
+            debug!("{:?}: Not yet in sync with {:?}, histories {:?} != {:?}", self.name, peer, our_history, history);
+        }
+    }
+
     pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
         self.state.peers.new_conn(self.node_tx.clone(), addr).await?;
 
@@ -131,28 +602,554 @@ impl Node {
         }
     }
 
+    /// Selects the key pair used to sign transfers made with `transfer`.
+    pub fn use_keys(&self, keys: KeyPair) {
+        *self.wallet.write().unwrap() = Some(keys);
+    }
+
+    pub fn get_keys(&self) -> Option<KeyPair> {
+        self.wallet.read().unwrap().clone()
+    }
+
+    /// Signs a DKK transfer from the selected account and broadcasts it.
+    pub async fn transfer(&self, to: Id, amount: Amount) -> anyhow::Result<()> {
+        self.transfer_asset(to, AssetId::DKK, amount).await
+    }
+
+    pub async fn transfer_asset(&self, to: Id, asset: AssetId, amount: Amount) -> anyhow::Result<()> {
+        let trx = self.sign_transfer(to, asset, amount, vec![], None)?;
+        self.send(trx).await;
+
+        Ok(())
+    }
+
+    /// Pays every output in a single transaction, applied all or nothing.
+    pub async fn transfer_batch(&self, asset: AssetId, mut outputs: Vec<Output>) -> anyhow::Result<()> {
+        if outputs.is_empty() {
+            return Err(anyhow!("A batch needs at least one output"))
+        }
+        let first = outputs.remove(0);
+        let trx = self.sign_transfer(first.to, asset, first.amount, outputs, None)?;
+        self.send(trx).await;
+
+        Ok(())
+    }
+
+    /// Like `transfer_asset`, but holds the amount in escrow until released,
+    /// see `release`. Returns the id of the escrow account.
+    pub async fn transfer_locked(&self, to: Id, asset: AssetId, amount: Amount, lock: Lock) -> anyhow::Result<Id> {
+        let trx = self.sign_transfer(to, asset, amount, vec![], Some(lock))?;
+        let escrow = trx.trx.escrow_id();
+        self.send(trx).await;
+
+        Ok(escrow)
+    }
+
+    fn sign_transfer(&self, to: Id, asset: AssetId, amount: Amount, outputs: Vec<Output>, lock: Option<Lock>) -> anyhow::Result<SignedAccountTransaction> {
+        let keys = self.get_keys().ok_or_else(|| anyhow!("No key selected, see ':keys use <name>'"))?;
+        let now = Timestamp::since_unix()?;
+        let trx = AccountTransaction {
+            from: keys.public,
+            to,
+            asset,
+            amount,
+            fee: self.min_relay_fee(1 + outputs.len()).ok_or_else(|| anyhow!("The fee for {} outputs overflows", 1 + outputs.len()))?,
+            lock,
+            outputs,
+            valid_after: None,
+            valid_until: Some(Timestamp::from_millis(now.as_millis() + TRANSFER_VALIDITY.as_millis() as u64)),
+            timestamp: now,
+        };
+
+        keys.private.sign(trx)
+    }
+
+    /// The fee a transaction with this many outputs needs to be relayed,
+    /// `None` if it overflows. The outputs are counted in transactions from
+    /// peers, so they can be made to.
+    pub fn min_relay_fee(&self, outputs: usize) -> Option<Amount> {
+        let outputs = i64::try_from(outputs).ok()?;
+        self.config.min_relay_fee.0.checked_mul(outputs).map(Amount)
+    }
+
+    /// Releases an escrow now, with the preimage of its hash lock if it has
+    /// one, and broadcasts the release. Returns who got the money.
+    pub fn release(&self, escrow: Id, preimage: Option<Vec<u8>>) -> anyhow::Result<Id> {
+        let now = Timestamp::since_unix()?;
+        self.release_escrow(EscrowRelease { escrow, preimage, timestamp: now }, now)
+    }
+
+    /// Applies a release from the peer at `from`. Releases of escrows we
+    /// haven't seen locked yet are held back until the lock arrives, the
+    /// first one received for each escrow.
+    pub(crate) fn receive_release(&self, release: EscrowRelease, from: SocketAddr) {
+        let Ok(received) = Timestamp::since_unix() else { return };
+        if self.state.ledger.is_released(&release.escrow) {
+            trace!("{:?}: {} is already released", self.name, release.escrow);
+            return
+        }
+        if self.state.ledger.escrow(&release.escrow).is_none() {
+            let from_peer = self.pending_releases.iter().filter(|p| p.value().2 == from).count();
+            if self.pending_releases.len() < MAX_PENDING_RELEASES && from_peer < MAX_PENDING_RELEASES_PER_PEER {
+                trace!("{:?}: Holding back release of {}", self.name, release.escrow);
+                self.pending_releases.entry(release.escrow).or_insert((release, received, from));
+            }
+            return
+        }
+        if let Err(e) = self.release_escrow(release, received) {
+            debug!("{:?}: Not releasing: {}", self.name, e);
+        }
+    }
+
+    /// Applies a release held back for `escrow`, now that it is locked.
+    fn release_pending(&self, escrow: &Id) {
+        if let Some((_, (release, received, _))) = self.pending_releases.remove(escrow) {
+            if let Err(e) = self.release_escrow(release, received) {
+                debug!("{:?}: Not releasing: {}", self.name, e);
+            }
+        }
+    }
+
+    /// The timestamp decides time locks and hash lock deadlines, so it must
+    /// be close to when the release was `received`.
+    fn release_escrow(&self, release: EscrowRelease, received: Timestamp) -> anyhow::Result<Id> {
+        let drift = MAX_CLOCK_DRIFT.as_millis() as u64;
+        if release.timestamp.as_millis() > received.as_millis() + drift {
+            return Err(anyhow!("Release of {} is timestamped in the future", release.escrow))
+        }
+        if release.timestamp.as_millis() + drift < received.as_millis() {
+            return Err(anyhow!("Release of {} is timestamped in the past", release.escrow))
+        }
+        let to = self.state.ledger.release(&release)?;
+
+        info!("{:?}: Released {} to {}", self.name, release.escrow, to);
+        self.gossip(Packet::Release(release), Duration::ZERO);
+        Ok(to)
+    }
+
+    /// Registers `account`, funded with `deposit` from the selected account,
+    /// and broadcasts the registration, which both accounts sign.
+    pub async fn create_account(&self, account: &KeyPair, deposit: Amount, label: Option<String>) -> anyhow::Result<()> {
+        let keys = self.get_keys().ok_or_else(|| anyhow!("No key selected, see ':keys use <name>'"))?;
+        let creation = AccountCreation {
+            id: account.public,
+            from: keys.public,
+            deposit,
+            fee: self.config.genesis.consensus.accounts.registration_fee,
+            label,
+            timestamp: Timestamp::since_unix()?,
+        };
+        self.register(keys.private.sign_creation(creation, &account.private)?);
+
+        Ok(())
+    }
+
+    /// Applies a registration and passes it on. Registrations are only
+    /// applied once, so one we already know of isn't passed on again.
+    fn register(&self, signed: SignedAccountCreation) {
+        if !signed.verify() {
+            debug!("{:?}: Invalid signature on {:?}", self.name, signed.creation);
+            return
+        }
+        if let Err(e) = self.state.ledger.register(&signed.creation) {
+            debug!("{:?}: Not registering: {}", self.name, e);
+            return
+        }
+
+        info!("{:?}: Registered {}", self.name, signed.creation.id);
+        self.gossip(Packet::Register(signed), Duration::ZERO);
+    }
+
+    /// Issues `amount` of the asset `symbol` to the selected account and
+    /// broadcasts the issuance. The first issuance of an asset fixes its cap.
+    pub async fn issue_asset(&self, symbol: &str, cap: Amount, amount: Amount) -> anyhow::Result<AssetId> {
+        let keys = self.get_keys().ok_or_else(|| anyhow!("No key selected, see ':keys use <name>'"))?;
+        let issuance = AssetIssuance {
+            issuer: keys.public,
+            symbol: symbol.to_owned(),
+            cap,
+            amount,
+            timestamp: Timestamp::since_unix()?,
+        };
+        let asset = issuance.asset();
+        self.issue(keys.private.sign_issuance(issuance)?);
+
+        Ok(asset)
+    }
+
+    /// Applies an issuance and passes it on, unless it was applied before.
+    fn issue(&self, signed: SignedAssetIssuance) {
+        if !signed.verify() {
+            debug!("{:?}: Invalid signature on {:?}", self.name, signed.issuance);
+            return
+        }
+        if let Err(e) = self.state.ledger.issue(&signed.issuance) {
+            debug!("{:?}: Not issuing: {}", self.name, e);
+            return
+        }
+
+        info!("{:?}: Issued {:?} {}", self.name, signed.issuance.amount, signed.issuance.asset());
+        self.gossip(Packet::Issue(signed), Duration::ZERO);
+    }
+
     pub async fn send(&self, trx: SignedAccountTransaction) {
-        self.broadcast(trx.into())
+        self.metrics.trxs_received.inc();
+        if !Self::is_admissible(&trx.trx) {
+            self.metrics.reject(Rejection::Expired);
+            debug!("{:?}: Not sending {:?}, it expires too soon to reach every peer", self.name, trx);
+            return
+        }
+        if self.config.behaviour == Behaviour::Equivocating {
+            if let Some(keys) = self.get_keys().filter(|keys| keys.public == trx.trx.from) {
+                log_fail!(self.equivocate(&keys, trx.trx).await);
+                return
+            }
+        }
+        match self.config.dandelion {
+            Some(_) => self.stem(trx),
+            None => self.broadcast(trx),
+        }
+    }
+
+    /// Sends every peer a differently sized version of `trx`, signed by `keys`.
+    async fn equivocate(&self, keys: &KeyPair, trx: AccountTransaction) -> anyhow::Result<()> {
+        for (i, (_, peer)) in self.state.peers.clone_iter().enumerate() {
+            let amount = trx.amount.checked_add(Amount(i as i64)).ok_or_else(|| anyhow!("Amount overflows: {:?}", trx))?;
+            let version = keys.private.sign(AccountTransaction { amount, ..trx.clone() })?;
+            trace!("{:?}: Equivocating {:?} to {:?}", self.name, version, peer);
+            peer.send(Packet::Broadcast(version)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies a transaction, skipping the signature check if it has been verified before.
+    pub fn verify(&self, trx: &SignedAccountTransaction) -> bool {
+        let id = trx.trx.id();
+        if self.state.verified.contains_key(&id) {
+            return true
+        }
+
+        let is_valid = trx.verify();
+        if is_valid {
+            self.remember_verified(&trx.trx);
+        }
+        is_valid
+    }
+
+    /// Batch verifies the transactions that haven't been verified before,
+    /// returning the index of the first invalid transaction on failure.
+    pub fn verify_batch(&self, trxs: &[SignedAccountTransaction]) -> Result<(), usize> {
+        let (indices, unverified): (Vec<usize>, Vec<SignedAccountTransaction>) = trxs
+            .iter()
+            .enumerate()
+            .filter(|(_, trx)| !self.state.verified.contains_key(&trx.trx.id()))
+            .map(|(i, trx)| (i, trx.clone()))
+            .unzip();
+
+        SignedAccountTransaction::verify_batch(&unverified).map_err(|i| indices[i])?;
+        for trx in unverified {
+            self.remember_verified(&trx.trx);
+        }
+
+        Ok(())
+    }
+
+    /// Batch verifies `trxs` before broadcasting them, so the valid ones
+    /// are already known to be valid by the time they are broadcast.
+    fn receive_batch(&self, trxs: Vec<SignedAccountTransaction>) {
+        if trxs.len() > 1 {
+            if let Err(i) = self.verify_batch(&trxs) {
+                debug!("{:?}: Invalid signature in batch on {:?}, verifying one by one", self.name, trxs[i]);
+            }
+        }
+        for trx in trxs {
+            self.broadcast(trx);
+        }
+    }
+
+    /// Caches that the signature of `trx` is valid, until the transaction
+    /// expires or for `VERIFIED_EXPIRY`, whichever is sooner.
+    fn remember_verified(&self, trx: &AccountTransaction) {
+        if self.state.verified.len() >= MAX_VERIFIED {
+            return
+        }
+        let Ok(now) = Timestamp::since_unix() else { return };
+        let latest = Timestamp::from_millis(now.as_millis() + VERIFIED_EXPIRY.as_millis() as u64);
+        self.state.verified.insert(trx.id(), trx.valid_until.map_or(latest, |until| until.min(latest)));
+    }
+
+    /// Whether `trx` may be submitted now. It must stay valid for another
+    /// `MAX_CLOCK_DRIFT`, so peers whose clocks are a little ahead admit it too.
+    fn is_admissible(trx: &AccountTransaction) -> bool {
+        let Ok(now) = Timestamp::since_unix() else { return false };
+        let margin = now.as_millis() + MAX_CLOCK_DRIFT.as_millis() as u64;
+        trx.is_valid_at(now) && trx.valid_until.is_none_or(|until| until.as_millis() >= margin)
+    }
+
+    /// Whether `trx` is valid now, give or take `MAX_CLOCK_DRIFT`. Anything
+    /// our peers admitted passes, so our clock doesn't make us diverge.
+    fn is_current(trx: &AccountTransaction) -> bool {
+        let Ok(now) = Timestamp::since_unix() else { return false };
+        let drift = MAX_CLOCK_DRIFT.as_millis() as u64;
+        trx.valid_after.is_none_or(|after| after.as_millis() <= now.as_millis().saturating_add(drift))
+            && trx.valid_until.is_none_or(|until| until.as_millis().saturating_add(drift) >= now.as_millis())
+    }
+
+    /// Serves `render_metrics` over HTTP on `addr`, returning the address
+    /// actually bound, which differs from `addr` if its port is 0.
+    pub async fn serve_metrics(&self, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        info!("{:?}: Serving metrics on http://{}/metrics", self.name, addr);
+
+        tokio::spawn({
+            let node = self.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = skip_fail!(listener.accept().await);
+                    let node = node.clone();
+                    tokio::spawn(async move {
+                        // The request line is all we need, and it fits in the first read.
+                        let mut request = [0u8; 1024];
+                        let len = stream.read(&mut request).await.unwrap_or(0);
+                        let response = if request[..len].starts_with(b"GET /metrics ") {
+                            let body = node.render_metrics();
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                body.len(), body
+                            )
+                        } else {
+                            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+                        };
+                        log_fail!(stream.write_all(response.as_bytes()).await);
+                    });
+                }
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// The node's metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        self.metrics.render(&mut out);
+
+        let peers: Vec<(SocketAddr, Peer)> = self.state.peers.clone_iter().collect();
+        metrics::gauge(&mut out, "dkk_peers", "Connected peers.", peers.len() as u64);
+        metrics::gauge(&mut out, "dkk_history_transactions", "Transactions in the history.", self.state.history.len() as u64);
+        metrics::gauge(&mut out, "dkk_stempool_transactions", "Transactions stemmed, but not yet fluffed.", self.stempool.len() as u64);
+        metrics::gauge(
+            &mut out, "dkk_node_queue_len", "Packets received from peers waiting to be handled.",
+            (self.node_tx.max_capacity() - self.node_tx.capacity()) as u64,
+        );
+
+        let per_peer: [PeerMetric; 3] = [
+            ("dkk_peer_bytes_received_total", "Bytes received from each peer.", "counter", Peer::get_bytes_in),
+            ("dkk_peer_bytes_sent_total", "Bytes sent to each peer.", "counter", Peer::get_bytes_out),
+            ("dkk_peer_queue_len", "Packets waiting to be sent to each peer.", "gauge", |peer| peer.queue_len() as u64),
+        ];
+        for (name, help, kind, value) in per_peer {
+            metrics::header(&mut out, name, help, kind);
+            for (addr, peer) in &peers {
+                out.push_str(&format!("{}{{peer=\"{}\"}} {}\n", name, addr, value(peer)));
+            }
+        }
+
+        out
+    }
+
+    /// Sends every peer a transaction from a random account, with the amount
+    /// changed after signing, forever.
+    async fn spam(&self) {
+        let mut interval = tokio::time::interval(SPAM_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let keys = self.gen_keys();
+            let trx = AccountTransaction {
+                from: keys.public,
+                to: self.gen_keys().public,
+                asset: AssetId::DKK,
+                amount: Amount(1),
+                fee: Amount(0),
+                lock: None,
+                outputs: vec![],
+                valid_after: None,
+                valid_until: None,
+                timestamp: skip_fail!(Timestamp::since_unix()),
+            };
+            let mut signed = skip_fail!(keys.private.sign(trx));
+            signed.trx.amount = Amount(1_000_000);
+
+            self.gossip(Packet::Broadcast(signed), Duration::ZERO);
+        }
+    }
+
+    /// Sends an anonymous chat message to everyone on the network.
+    pub fn chat(&self, text: &str) -> anyhow::Result<()> {
+        self.relay_chat(ChatMessage::new(text.to_owned())?);
+        Ok(())
+    }
+
+    /// Receives every chat message seen from now on, including our own.
+    pub fn subscribe_chat(&self) -> broadcast::Receiver<ChatMessage> {
+        self.chat_tx.subscribe()
+    }
+
+    pub(crate) fn relay_chat(&self, msg: ChatMessage) {
+        if self.chat_seen.insert(msg.id, Instant::now()).is_some() {
+            return
+        }
+        if self.chat_seen.len() > MAX_CHAT_SEEN {
+            let oldest = self.chat_seen.iter().min_by_key(|seen| *seen.value()).map(|seen| *seen.key());
+            if let Some(id) = oldest {
+                self.chat_seen.remove(&id);
+            }
+        }
+
+        // No one may be subscribed, which is fine.
+        let _ = self.chat_tx.send(msg.clone());
+        self.gossip(Packet::Chat(msg), self.config.chat_delay);
+    }
+
+    /// Sends a packet to every peer, each after a random delay of up to `max_delay`.
+    fn gossip(&self, packet: Packet, max_delay: Duration) {
+        for peer in self.state.peers.clone_iter() {
+            let packet = packet.clone();
+            let delay = if max_delay.is_zero() {
+                Duration::ZERO
+            } else {
+                rand::thread_rng().gen_range(Duration::ZERO..=max_delay)
+            };
+
+            tokio::spawn(async move {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                peer.1.send(packet).await;
+            });
+        }
+    }
+
+    /// Passes a transaction on to the stem peer, or fluffs it if there is none.
+    fn stem(&self, trx: SignedAccountTransaction) {
+        let dandelion = match &self.config.dandelion {
+            Some(d) => d.clone(),
+            None => return self.broadcast(trx),
+        };
+
+        if !self.verify(&trx) {
+            debug!("{:?}: Dropping invalid stem {:?}", self.name, trx);
+            return
+        }
+
+        // Let the broadcast count it as expired.
+        if !Self::is_current(&trx.trx) {
+            return self.broadcast(trx)
+        }
+
+        // Either it has been fluffed already, or the stem looped back to us.
+        if self.state.history.contains(&trx.trx) || !self.stempool.insert(trx.trx.id()) {
+            return self.broadcast(trx)
+        }
+
+        let peer = match self.stem_peer(dandelion.epoch) {
+            Some(peer) => peer,
+            None => return self.broadcast(trx),
+        };
+        debug!("{:?}: Stemming {:?} to {:?}", self.name, trx, peer);
+
+        tokio::spawn({
+            let trx = trx.clone();
+            async move { peer.send(Packet::Stem(trx)).await; }
+        });
+
+        tokio::spawn({
+            let node = self.clone();
+            async move {
+                tokio::time::sleep(dandelion.embargo).await;
+                node.stempool.remove(&trx.trx.id());
+                if !node.state.history.contains(&trx.trx) {
+                    debug!("{:?}: Embargo of {:?} expired, fluffing", node.name, trx);
+                    node.broadcast(trx);
+                }
+            }
+        });
+    }
+
+    /// A random full node, kept for an epoch or until it disconnects.
+    fn stem_peer(&self, epoch: Duration) -> Option<Peer> {
+        let mut stem_peer = self.stem_peer.write().unwrap();
+        if let Some((picked, addr)) = *stem_peer {
+            if picked.elapsed() < epoch {
+                if let Some(peer) = self.state.peers.get(&addr) {
+                    return Some(peer)
+                }
+            }
+        }
+
+        let full_nodes: Vec<(SocketAddr, Peer)> = self.state.peers
+            .clone_iter()
+            .filter(|(_, peer)| !peer.get_features().contains(Features::LIGHT))
+            .collect();
+        let (addr, peer) = full_nodes.choose(&mut rand::thread_rng())?.clone();
+        *stem_peer = Some((Instant::now(), addr));
+
+        Some(peer)
     }
 
     fn broadcast(&self, trx: SignedAccountTransaction) {
         let conn = self.clone();
 
-        let is_trx_valid = trx.verify();
-        let is_trx_new = conn.state.history.insert(trx.trx.clone());
+        let is_trx_valid = self.verify(&trx);
+        let is_trx_current = is_trx_valid && Self::is_current(&trx.trx);
+        let min_fee = self.min_relay_fee(trx.trx.recipients().count());
+        // A fee that overflows can't be paid.
+        let is_fee_paid = is_trx_current && min_fee.is_some_and(|min_fee| trx.trx.fee >= min_fee);
+        // Invalid transactions are kept out of the history, or anyone could fill it up.
+        // Inserting before applying claims the transaction, so it isn't applied twice.
+        let is_trx_new = is_fee_paid && conn.state.history.insert(trx.trx.clone());
         
-        if is_trx_new && is_trx_valid {
+        if !is_trx_valid {
+            self.metrics.reject(Rejection::InvalidSignature);
+        } else if !is_trx_current {
+            self.metrics.reject(Rejection::Expired);
+            debug!("{:?}: Dropping {:?}, outside its validity window", self.name, trx);
+        } else if !is_fee_paid {
+            self.metrics.reject(Rejection::LowFee);
+            debug!("{:?}: Dropping {:?}, the fee is below the minimum relay fee", self.name, trx);
+        } else if !is_trx_new {
+            self.metrics.reject(Rejection::Duplicate);
+        } else {
             info!(" {:?}: {:?}", self.name, trx);
 
-            log_fail!(conn.state.ledger.update(&trx.trx));
+            if !self.config.light {
+                match conn.state.ledger.update(&trx.trx) {
+                    Ok(()) => {
+                        self.metrics.trxs_applied.inc();
+                        if trx.trx.lock.is_some() {
+                            self.release_pending(&trx.trx.escrow_id());
+                        }
+                        let now = Timestamp::since_unix().map(|t| t.as_millis()).unwrap_or_default();
+                        self.metrics.confirmation_latency.observe(now.saturating_sub(trx.trx.timestamp.as_millis()));
+                    }
+                    Err(e) => {
+                        conn.state.history.remove(&trx.trx);
+                        self.metrics.reject(Rejection::Ledger);
+                        error!("{:?}: Failed to apply {:?}: {}", self.name, trx, e);
+                        return
+                    }
+                }
+            }
 
-            for peer in conn.state.peers.clone_iter() {
-                let trx = trx.clone();
-                tokio::spawn(async move {
-                    let packet = Packet::Broadcast(trx);
-                    peer.1.send(packet).await;
-                });
+            if self.config.behaviour == Behaviour::Withholding {
+                trace!("{:?}: Withholding {:?}", self.name, trx);
+                return
             }
+
+            conn.gossip(Packet::Broadcast(trx), Duration::ZERO);
         }
     }
 }
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, fs, net::SocketAddr, path::Path};
+
+use anyhow::anyhow;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{hd, merkle::Hash, types::*};
+
+/// The balance of each of the ten genesis accounts of `Genesis::testnet`.
+const TESTNET_BALANCE: Amount = Amount(1_000_000);
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct ConsensusParams {
+    /// The length of a lottery slot, in milliseconds.
+    pub slot_length_ms: u64,
+    /// The hardness of the lottery, the probability of a draw winning a slot
+    /// is roughly `1 / hardness`.
+    pub hardness: u64,
+    /// The maximum number of transactions in a block.
+    pub block_size: usize,
+    #[serde(default)]
+    pub accounts: AccountRules,
+}
+
+/// How accounts come into existence.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct AccountRules {
+    /// Whether a transfer to an unregistered account registers it, with the
+    /// sender paying `registration_fee` on top. If not, such transfers are
+    /// rejected, and accounts must be registered explicitly.
+    pub implicit_registration: bool,
+    /// The fee of registering an account, explicitly or implicitly.
+    pub registration_fee: Amount,
+    /// The smallest deposit an explicit registration may fund an account with.
+    pub min_deposit: Amount,
+}
+
+impl Default for AccountRules {
+    fn default() -> AccountRules {
+        AccountRules {
+            implicit_registration: true,
+            registration_fee: Amount(0),
+            min_deposit: Amount(0),
+        }
+    }
+}
+
+impl Default for ConsensusParams {
+    fn default() -> ConsensusParams {
+        ConsensusParams {
+            slot_length_ms: 1000,
+            hardness: 10,
+            block_size: 100,
+            accounts: AccountRules::default(),
+        }
+    }
+}
+
+/// The chain specification, everything nodes must agree on before they can
+/// talk to each other. Stored as JSON, see the README for an example.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct Genesis {
+    pub network_id: String,
+    /// The initial seed of the lottery.
+    pub seed: String,
+    #[serde(default)]
+    pub balances: BTreeMap<Id, Amount>,
+    #[serde(default)]
+    pub consensus: ConsensusParams,
+    /// Peers to connect to on startup. Not part of the genesis id, as the
+    /// bootstrap peers of a network may change over time.
+    #[serde(default)]
+    pub bootstrap_peers: Vec<SocketAddr>,
+}
+
+impl Default for Genesis {
+    fn default() -> Genesis {
+        Genesis::dev()
+    }
+}
+
+impl Genesis {
+    /// A local development chain without any money in it.
+    pub fn dev() -> Genesis {
+        Genesis {
+            network_id: "dkk-dev".to_owned(),
+            seed: String::new(),
+            balances: BTreeMap::new(),
+            consensus: ConsensusParams::default(),
+            bootstrap_peers: vec![],
+        }
+    }
+
+    /// A test chain where each of the ten accounts derived from
+    /// `hd::GENESIS_MNEMONIC` starts out with 10^6 DKK.
+    pub fn testnet() -> anyhow::Result<Genesis> {
+        let balances = hd::genesis_accounts(10)?
+            .into_iter()
+            .map(|keys| (keys.public, TESTNET_BALANCE))
+            .collect();
+
+        Ok(Genesis {
+            network_id: "dkk-testnet".to_owned(),
+            seed: hd::GENESIS_MNEMONIC.to_owned(),
+            balances,
+            ..Genesis::dev()
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Genesis> {
+        let json = fs::read_to_string(path)?;
+        let genesis: Genesis = serde_json::from_str(&json)?;
+        genesis.validate()?;
+
+        Ok(genesis)
+    }
+
+    /// Checks that no balance is negative, and that the total supply fits in an `Amount`.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some((id, amount)) = self.balances.iter().find(|(_, amount)| amount.0 < 0) {
+            return Err(anyhow!("Genesis balance of {} is negative: {:?}", id, amount))
+        }
+        if self.total_supply().is_none() {
+            return Err(anyhow!("Genesis balances overflow"))
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(())
+    }
+
+    /// Identifies the chain. Nodes with different genesis ids refuse to connect.
+    pub fn id(&self) -> Hash {
+        let genesis = Genesis {
+            bootstrap_peers: vec![],
+            ..self.clone()
+        };
+        let bytes = bincode::encode_to_vec(&genesis, bincode::config::standard())
+            .expect("The genesis can always be encoded");
+
+        Hash(Sha256::digest(bytes).into())
+    }
+
+    /// `None` if it overflows, which it can't for a validated genesis.
+    pub fn total_supply(&self) -> Option<Amount> {
+        self.balances.values().try_fold(Amount(0), |total, amount| total.checked_add(*amount))
+    }
+}
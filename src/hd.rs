@@ -0,0 +1,101 @@
+use anyhow::anyhow;
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha512;
+
+use crate::types::*;
+
+const HARDENED: u32 = 0x8000_0000;
+
+/// The SLIP-0010 curve seed for ed25519.
+const ED25519_SEED: &[u8] = b"ed25519 seed";
+
+/// The well known BIP-39 test phrase. The genesis accounts are derived from
+/// this, so never hold real funds on keys derived from it.
+pub const GENESIS_MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+/// Generates a fresh 24 word BIP-39 mnemonic.
+pub fn generate_mnemonic() -> anyhow::Result<String> {
+    let mut entropy = [0u8; 32];
+    OsRng.fill_bytes(&mut entropy);
+
+    Ok(Mnemonic::from_entropy(&entropy)?.to_string())
+}
+
+/// The derivation path of the `index`th account of a wallet, `m/44'/1'/index'`.
+/// Only hardened derivation is defined for ed25519.
+pub fn account_path(index: u32) -> String {
+    format!("m/44'/1'/{}'", index)
+}
+
+/// Derives the key pair of the `index`th account from a mnemonic phrase.
+pub fn derive_account(phrase: &str, passphrase: &str, index: u32) -> anyhow::Result<KeyPair> {
+    let seed = Mnemonic::parse_normalized(phrase)?.to_seed(passphrase);
+
+    Ok(ExtendedKey::master(&seed).derive_path(&account_path(index))?.keypair())
+}
+
+/// Derives the `n` genesis accounts from `GENESIS_MNEMONIC`.
+pub fn genesis_accounts(n: u32) -> anyhow::Result<Vec<KeyPair>> {
+    (0..n).map(|i| derive_account(GENESIS_MNEMONIC, "", i)).collect()
+}
+
+/// A SLIP-0010 extended private key for ed25519.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ExtendedKey {
+    pub secret: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        Self::from_hmac(ED25519_SEED, &[seed])
+    }
+
+    /// Derives the hardened child at `index`, the hardened bit is set if missing.
+    pub fn derive_child(&self, index: u32) -> ExtendedKey {
+        let index = index | HARDENED;
+        Self::from_hmac(&self.chain_code, &[&[0u8], &self.secret, &index.to_be_bytes()])
+    }
+
+    /// Derives a path such as `m/44'/1'/0'`. Every segment must be hardened.
+    pub fn derive_path(&self, path: &str) -> anyhow::Result<ExtendedKey> {
+        let mut segments = path.split('/');
+        if segments.next() != Some("m") {
+            return Err(anyhow!("Derivation path '{}' must start with 'm'", path))
+        }
+
+        segments.try_fold(self.clone(), |key, segment| {
+            let index = segment
+                .strip_suffix('\'')
+                .or_else(|| segment.strip_suffix('h'))
+                .ok_or_else(|| anyhow!("Segment '{}' of '{}' is not hardened", segment, path))?
+                .parse::<u32>()?;
+            if index >= HARDENED {
+                return Err(anyhow!("Segment '{}' of '{}' is out of range", segment, path))
+            }
+
+            Ok(key.derive_child(index))
+        })
+    }
+
+    pub fn keypair(&self) -> KeyPair {
+        KeyPair::from_secret(&self.secret)
+    }
+
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> ExtendedKey {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        for d in data {
+            mac.update(d);
+        }
+        let bytes = mac.finalize().into_bytes();
+
+        let mut secret = [0u8; 32];
+        let mut chain_code = [0u8; 32];
+        secret.copy_from_slice(&bytes[..32]);
+        chain_code.copy_from_slice(&bytes[32..]);
+
+        ExtendedKey { secret, chain_code }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use anyhow::anyhow;
+use base64ct::{Base64, Encoding};
+use bincode::{Decode, Encode};
+use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{hd, types::*};
+
+const KEYSTORE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// scrypt parameters, N = 2^15, r = 8, p = 1 as recommended for interactive logins.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// The on-disk layout of a keystore. Everything but the kdf salt and the
+/// aead nonce is encrypted.
+#[derive(Decode, Encode)]
+struct KeystoreFile {
+    version: u8,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+/// The plaintext contents of a keystore.
+#[derive(Decode, Encode, Default)]
+struct KeystoreContents {
+    keys: BTreeMap<String, [u8; 32]>,
+    selected: Option<String>,
+}
+
+/// A password-encrypted collection of named ed25519 keys.
+pub struct Keystore {
+    path: PathBuf,
+    password: String,
+    contents: KeystoreContents,
+}
+
+impl Keystore {
+    /// Opens the keystore at `path`, creating an empty one if the file does
+    /// not exist yet. Fails if the password is wrong or the file is corrupt.
+    pub fn open(path: impl AsRef<Path>, password: &str) -> anyhow::Result<Keystore> {
+        let path = path.as_ref().to_path_buf();
+
+        let contents = if path.exists() {
+            let bytes = fs::read(&path)?;
+            let (file, _): (KeystoreFile, _) = bincode::decode_from_slice(&bytes, bincode::config::standard())?;
+            Self::decrypt(&file, password)?
+        } else {
+            KeystoreContents::default()
+        };
+
+        Ok(Keystore {
+            path,
+            password: password.to_owned(),
+            contents,
+        })
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let file = Self::encrypt(&self.contents, &self.password)?;
+        let bytes = bincode::encode_to_vec(&file, bincode::config::standard())?;
+
+        // Write to a temporary file first, so a crash never leaves a half written keystore.
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn generate(&mut self, name: &str) -> anyhow::Result<KeyPair> {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        self.insert(name, secret)
+    }
+
+    /// Imports a base64 encoded secret key, as produced by `export`.
+    pub fn import(&mut self, name: &str, secret: &str) -> anyhow::Result<KeyPair> {
+        let decoded = Base64::decode_vec(secret)
+            .map_err(|e| anyhow!("Invalid secret key: {}", e))?;
+        let bytes: [u8; 32] = decoded
+            .try_into()
+            .map_err(|v: Vec<u8>| anyhow!("Invalid secret key: expected 32 bytes, got {}", v.len()))?;
+        self.insert(name, bytes)
+    }
+
+    /// Restores the `index`th account of an HD wallet from its mnemonic phrase.
+    pub fn restore(&mut self, name: &str, phrase: &str, index: u32) -> anyhow::Result<KeyPair> {
+        let keys = hd::derive_account(phrase, "", index)?;
+        self.insert(name, keys.private.to_bytes())
+    }
+
+    pub fn export(&self, name: &str) -> anyhow::Result<String> {
+        let secret = self.contents.keys
+            .get(name)
+            .ok_or_else(|| anyhow!("No key named '{}'", name))?;
+
+        Ok(Base64::encode_string(secret))
+    }
+
+    pub fn get(&self, name: &str) -> Option<KeyPair> {
+        self.contents.keys.get(name).map(KeyPair::from_secret)
+    }
+
+    pub fn list(&self) -> Vec<(String, PublicKey)> {
+        self.contents.keys
+            .iter()
+            .map(|(name, secret)| (name.clone(), KeyPair::from_secret(secret).public))
+            .collect()
+    }
+
+    pub fn select(&mut self, name: &str) -> anyhow::Result<KeyPair> {
+        let keys = self.get(name).ok_or_else(|| anyhow!("No key named '{}'", name))?;
+        self.contents.selected = Some(name.to_owned());
+        self.save()?;
+
+        Ok(keys)
+    }
+
+    pub fn selected(&self) -> Option<(String, KeyPair)> {
+        let name = self.contents.selected.clone()?;
+        let keys = self.get(&name)?;
+        Some((name, keys))
+    }
+
+    fn insert(&mut self, name: &str, secret: [u8; 32]) -> anyhow::Result<KeyPair> {
+        if self.contents.keys.contains_key(name) {
+            return Err(anyhow!("A key named '{}' already exists", name))
+        }
+        self.contents.keys.insert(name.to_owned(), secret);
+        self.save()?;
+
+        Ok(KeyPair::from_secret(&secret))
+    }
+
+    fn derive_key(password: &str, salt: &[u8]) -> anyhow::Result<Key> {
+        let params = scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, 32)?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)?;
+
+        Ok(key.into())
+    }
+
+    fn encrypt(contents: &KeystoreContents, password: &str) -> anyhow::Result<KeystoreFile> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let plaintext = bincode::encode_to_vec(contents, bincode::config::standard())?;
+        let cipher = ChaCha20Poly1305::new(&Self::derive_key(password, &salt)?);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|_| anyhow!("Failed to encrypt keystore"))?;
+
+        Ok(KeystoreFile {
+            version: KEYSTORE_VERSION,
+            salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn decrypt(file: &KeystoreFile, password: &str) -> anyhow::Result<KeystoreContents> {
+        if file.version != KEYSTORE_VERSION {
+            return Err(anyhow!("Unsupported keystore version {}", file.version))
+        }
+
+        let cipher = ChaCha20Poly1305::new(&Self::derive_key(password, &file.salt)?);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&file.nonce), file.ciphertext.as_ref())
+            .map_err(|_| anyhow!("Wrong password or corrupt keystore"))?;
+        let (contents, _) = bincode::decode_from_slice(&plaintext, bincode::config::standard())?;
+
+        Ok(contents)
+    }
+}
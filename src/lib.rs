@@ -0,0 +1,16 @@
+pub mod types;
+pub mod address;
+pub mod bench;
+pub mod client;
+pub mod genesis;
+pub mod hd;
+pub mod keystore;
+pub mod merkle;
+pub mod metrics;
+pub mod peer;
+mod test;
+
+#[macro_use]
+extern crate log;
+
+mod macros;
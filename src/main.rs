@@ -1,21 +1,16 @@
-#![feature(is_some_and)]
-
 use std::{io::Write, net::SocketAddr, str::FromStr, thread::sleep, time::Duration};
 
-mod types;
-mod client;
-mod peer;
-mod test;
-
-use peer::*;
-use types::*;
-use client::*;
+use p2p::{bench, client::*, genesis::*, hd, keystore::*, types::*, skip_fail, verify_len};
 
 #[macro_use]
 extern crate log;
 
-#[macro_use]
-mod macros;
+const HISTORY_PAGE_SIZE: usize = 10;
+
+/// The fee paid and required for relaying, as in Exercise E.
+const DEFAULT_FEE: Amount = Amount(1);
+
+const COMMANDS: &str = "Anything not starting with ':' is sent as an anonymous chat message. Available commands are: ':connect <ip:port>, :compare <ip:port>, :peers, :balances, :exit, :send <to> <amount> [asset], :batch <to>:<amount>..., :issue <symbol> <cap> <amount>, :lock <to> <amount> <secs>, :htlc <to> <amount> <hash> <secs>, :release <escrow> [preimage], :register <key> <deposit> [label], :history <id> [page], :keys <new|list|import|export|use|mnemonic|restore>, :multisig <address|propose|sign|combine|submit>'";
 
 fn prompt(name: &str) -> String {
     let mut line = String::new();
@@ -33,25 +28,111 @@ fn prompt(name: &str) -> String {
     }
 }
 
+/// The value following `name` on the command line, as in `--genesis <path>`.
+fn arg_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Parses a comma separated list, as in `--byzantine 0,0.25,0.5`.
+fn parse_list<T: FromStr>(s: &str) -> anyhow::Result<Vec<T>> where T::Err: std::error::Error + Send + Sync + 'static {
+    Ok(s.split(',').map(|x| x.trim().parse()).collect::<Result<_, _>>()?)
+}
+
+fn parse_policy(args: &[&str]) -> anyhow::Result<MultisigPolicy> {
+    let (threshold, keys) = args.split_first().ok_or_else(|| anyhow::anyhow!("Missing threshold"))?;
+    let keys = keys
+        .iter()
+        .map(|k| Id::from_str(k))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    MultisigPolicy::new(threshold.parse()?, keys)
+}
+
+fn parse_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("'{}' is not hex", s))
+    }
+    (0..s.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?)).collect()
+}
+
+/// The time `secs` seconds from now.
+fn in_secs(secs: &str) -> anyhow::Result<Timestamp> {
+    let secs: u64 = secs.parse()?;
+    let now = Timestamp::since_unix()?.as_millis();
+    secs.checked_mul(1000)
+        .and_then(|millis| now.checked_add(millis))
+        .map(Timestamp::from_millis)
+        .ok_or_else(|| anyhow::anyhow!("{} seconds from now is too far in the future", secs))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        let default = bench::BenchConfig::default();
+        let config = bench::BenchConfig {
+            nodes: arg_value("--nodes").map(|n| n.parse()).transpose()?.unwrap_or(default.nodes),
+            trxs: arg_value("--trxs").map(|n| n.parse()).transpose()?.unwrap_or(default.trxs),
+            byzantine: arg_value("--byzantine").map(|s| parse_list(&s)).transpose()?.unwrap_or(default.byzantine),
+            behaviour: arg_value("--behaviour").map(|b| b.parse()).transpose()?.unwrap_or(default.behaviour),
+            timeout: default.timeout,
+        };
+        bench::run(&config).await?;
+
+        return Ok(())
+    }
+
     println!("Please type in a user name:");
     let username = prompt("");
 
-    let node= Node::new(&username).await?;
+    let genesis = match arg_value("--genesis") {
+        Some(path) => Genesis::load(path)?,
+        None => Genesis::dev(),
+    };
+    println!("Joining {} ({:?})", genesis.network_id, genesis.id());
+
+    let config = NodeConfig {
+        genesis,
+        light: std::env::args().any(|arg| arg == "--light"),
+        min_relay_fee: DEFAULT_FEE,
+        chat_delay: match arg_value("--chat-delay") {
+            Some(ms) => Duration::from_millis(ms.parse()?),
+            None => Duration::ZERO,
+        },
+        dandelion: std::env::args().any(|arg| arg == "--dandelion").then(DandelionConfig::default),
+        metrics_addr: arg_value("--metrics").map(|addr| addr.parse()).transpose()?,
+        behaviour: arg_value("--behaviour").map(|b| b.parse()).transpose()?.unwrap_or_default(),
+    };
+    let node= Node::with_config(&username, config).await?;
+
+    let keystore_path = format!("{}.keystore", username);
+    let password = rpassword::prompt_password(format!("Password for {}: ", keystore_path))?;
+    let mut keystore = Keystore::open(&keystore_path, &password)?;
+    if let Some((name, keys)) = keystore.selected() {
+        println!("Using key '{}': {}", name, keys.public);
+        node.use_keys(keys);
+    }
 
     sleep(Duration::from_secs(1));
 
     println!("Accepting connections on: {:#}", node.get_address().to_string());
-    println!("Available commands are: ':connect <ip:port>, :peers, :balances, :exit, :send <to> <amount>'");
+    println!("{}", COMMANDS);
+
+    tokio::spawn({
+        let mut chat = node.subscribe_chat();
+        async move {
+            while let Ok(msg) = chat.recv().await {
+                println!("󰍩 {}", msg.text);
+            }
+        }
+    });
 
     loop {
-        let input = prompt("");
-        let input: Vec<&str> = input.split_whitespace().collect();
+        let line = prompt("");
+        let input: Vec<&str> = line.split_whitespace().collect();
 
-        match input.get(0) {
+        match input.first() {
             Some(&":connect") => {
                 verify_len!(":connect", input.len(), 2);
 
@@ -66,7 +147,58 @@ async fn main() -> anyhow::Result<()> {
             Some(&":balances") => {
                 verify_len!(":balances", input.len(), 1);
 
-                println!("{:?}", node.get_ledger());
+                if node.config.light {
+                    for (name, id) in keystore.list() {
+                        match node.verified_balance(&id).await {
+                            Ok(amount) => println!("{}: {:?} DKK", name, amount),
+                            Err(e) => println!("{}: {}", name, e),
+                        }
+                    }
+                } else {
+                    println!("{:?}", node.get_ledger());
+                }
+            }
+            Some(&":history") => {
+                if input.len() != 2 && input.len() != 3 {
+                    error!("Usage: ':history <id> [page]'");
+                    continue;
+                }
+
+                let id = skip_fail!(Id::from_str(input[1]));
+                let page: usize = match input.get(2) {
+                    Some(page) => skip_fail!(page.parse()),
+                    None => 0,
+                };
+                let filter = HistoryFilter {
+                    offset: page * HISTORY_PAGE_SIZE,
+                    limit: HISTORY_PAGE_SIZE,
+                    ..Default::default()
+                };
+
+                for (trx, balance) in node.get_balance_history(&id, &filter) {
+                    match balance {
+                        Some(balance) => println!("{:?}: {} (balance: {:?})", trx.timestamp, trx, balance),
+                        None => println!("{:?}: {}", trx.timestamp, trx),
+                    }
+                }
+            }
+            Some(&":compare") => {
+                verify_len!(":compare", input.len(), 2);
+
+                let addr = skip_fail!(SocketAddr::from_str(input[1]));
+                let divergence = skip_fail!(node.compare(addr).await);
+                if divergence.is_empty() {
+                    println!("In sync with {}", addr);
+                    continue;
+                }
+
+                for diff in &divergence.accounts {
+                    println!("{} ({}): {:?} here, {:?} at {}", diff.id, diff.asset, diff.ours, diff.theirs, addr);
+                }
+                if let Some((ours, theirs)) = &divergence.first_difference {
+                    let show = |trx: &Option<AccountTransaction>| trx.as_ref().map_or("nothing".to_owned(), |trx| trx.to_string());
+                    println!("Histories first differ at {} here, {} at {}", show(ours), show(theirs), addr);
+                }
             }
             Some(&":exit") => {
                 verify_len!(":exit", input.len(), 1);
@@ -74,14 +206,223 @@ async fn main() -> anyhow::Result<()> {
                 break;
             }
             Some(&":send") => {
-                //verify_len!(":send", input.len(), 3);
-                //let from: Id.from(input[1].to_string())
-                //let to = Id(input[2].to_string());
-                //let amount = skip_fail!(input[3].parse());
-                //node.send(to, amount).await;
+                if input.len() != 3 && input.len() != 4 {
+                    error!("Usage: ':send <to> <amount> [asset]'");
+                    continue;
+                }
+
+                let to = skip_fail!(Id::from_str(input[1]));
+                let amount = Amount(skip_fail!(input[2].parse()));
+                let asset = match input.get(3) {
+                    Some(asset) => skip_fail!(AssetId::from_str(asset)),
+                    None => AssetId::DKK,
+                };
+                skip_fail!(node.transfer_asset(to, asset, amount).await);
+            }
+            Some(&":batch") => {
+                if input.len() < 2 {
+                    error!("Usage: ':batch <to>:<amount>...'");
+                    continue;
+                }
+
+                let mut outputs = vec![];
+                for output in &input[1..] {
+                    let (to, amount) = match output.split_once(':') {
+                        Some(output) => output,
+                        None => {
+                            error!("Outputs are written <to>:<amount>, not '{}'", output);
+                            break;
+                        }
+                    };
+                    outputs.push(Output { to: skip_fail!(Id::from_str(to)), amount: Amount(skip_fail!(amount.parse())) });
+                }
+                if outputs.len() != input.len() - 1 {
+                    continue;
+                }
+                skip_fail!(node.transfer_batch(AssetId::DKK, outputs).await);
+            }
+            Some(&":lock") => {
+                verify_len!(":lock", input.len(), 4);
+
+                let to = skip_fail!(Id::from_str(input[1]));
+                let amount = Amount(skip_fail!(input[2].parse()));
+                let lock = Lock::Time(skip_fail!(in_secs(input[3])));
+                println!("Escrow: {}", skip_fail!(node.transfer_locked(to, AssetId::DKK, amount, lock).await));
+            }
+            Some(&":htlc") => {
+                verify_len!(":htlc", input.len(), 5);
+
+                let to = skip_fail!(Id::from_str(input[1]));
+                let amount = Amount(skip_fail!(input[2].parse()));
+                let hash = match skip_fail!(parse_hex(input[3])).try_into() {
+                    Ok(hash) => hash,
+                    Err(_) => {
+                        error!("The hash must be 32 bytes of hex");
+                        continue;
+                    }
+                };
+                let lock = Lock::Hash { hash, deadline: skip_fail!(in_secs(input[4])) };
+                println!("Escrow: {}", skip_fail!(node.transfer_locked(to, AssetId::DKK, amount, lock).await));
+            }
+            Some(&":release") => {
+                if input.len() != 2 && input.len() != 3 {
+                    error!("Usage: ':release <escrow> [preimage]'");
+                    continue;
+                }
+
+                let escrow = skip_fail!(Id::from_str(input[1]));
+                let preimage = match input.get(2) {
+                    Some(preimage) => Some(skip_fail!(parse_hex(preimage))),
+                    None => None,
+                };
+                println!("Released to {}", skip_fail!(node.release(escrow, preimage)));
+            }
+            Some(&":issue") => {
+                verify_len!(":issue", input.len(), 4);
+
+                let cap = Amount(skip_fail!(input[2].parse()));
+                let amount = Amount(skip_fail!(input[3].parse()));
+                let asset = skip_fail!(node.issue_asset(input[1], cap, amount).await);
+                println!("{}: {}", input[1], asset);
+            }
+            Some(&":register") => {
+                if input.len() < 3 {
+                    error!("Usage: ':register <key> <deposit> [label]'");
+                    continue;
+                }
+
+                // The account being registered has to sign too, so its key must be in the keystore.
+                let account = skip_fail!(keystore.get(input[1]).ok_or_else(|| anyhow::anyhow!("No key named '{}'", input[1])));
+                let deposit = Amount(skip_fail!(input[2].parse()));
+                let label = Some(input[3..].join(" ")).filter(|label| !label.is_empty());
+                skip_fail!(node.create_account(&account, deposit, label).await);
+            }
+            Some(&":multisig") => {
+                match input.get(1) {
+                    Some(&"address") => {
+                        let policy = skip_fail!(parse_policy(&input[2..]));
+                        println!("{}-of-{}: {}", policy.threshold(), policy.keys().len(), policy.id());
+                    }
+                    Some(&"propose") => {
+                        if input.len() < 6 {
+                            error!("Usage: ':multisig propose <to> <amount> <m> <pk>...'");
+                            continue;
+                        }
+
+                        let policy = skip_fail!(parse_policy(&input[4..]));
+                        let trx = AccountTransaction {
+                            from: policy.id(),
+                            to: skip_fail!(Id::from_str(input[2])),
+                            asset: AssetId::DKK,
+                            amount: Amount(skip_fail!(input[3].parse())),
+                            fee: node.config.min_relay_fee,
+                            lock: None,
+                            outputs: vec![],
+                            valid_after: None,
+                            valid_until: None,
+                            timestamp: skip_fail!(Timestamp::since_unix()),
+                        };
+                        let strx = skip_fail!(SignedAccountTransaction::multisig(policy, trx));
+                        println!("{}", skip_fail!(strx.to_base64()));
+                    }
+                    Some(&"sign") => {
+                        verify_len!(":multisig sign", input.len(), 3);
+
+                        let keys = match node.get_keys() {
+                            Some(keys) => keys,
+                            None => {
+                                error!("No key selected, see ':keys use <name>'");
+                                continue;
+                            }
+                        };
+                        let mut strx = skip_fail!(SignedAccountTransaction::from_base64(input[2]));
+                        skip_fail!(strx.add_signature(&keys.private));
+                        println!("{}", skip_fail!(strx.to_base64()));
+                    }
+                    Some(&"combine") => {
+                        if input.len() < 4 {
+                            error!("Usage: ':multisig combine <trx> <trx>...'");
+                            continue;
+                        }
+
+                        let mut strx = skip_fail!(SignedAccountTransaction::from_base64(input[2]));
+                        for other in &input[3..] {
+                            let other = skip_fail!(SignedAccountTransaction::from_base64(other));
+                            skip_fail!(strx.merge(&other));
+                        }
+                        println!("{}", skip_fail!(strx.to_base64()));
+                    }
+                    Some(&"submit") => {
+                        verify_len!(":multisig submit", input.len(), 3);
+
+                        let strx = skip_fail!(SignedAccountTransaction::from_base64(input[2]));
+                        if !strx.verify() {
+                            error!("Transaction does not have enough valid signatures");
+                            continue;
+                        }
+                        node.send(strx).await;
+                    }
+                    _ => println!("Available multisig commands are: ':multisig address <m> <pk>..., :multisig propose <to> <amount> <m> <pk>..., :multisig sign <trx>, :multisig combine <trx> <trx>..., :multisig submit <trx>'"),
+                }
+            }
+            Some(&":keys") => {
+                match input.get(1) {
+                    Some(&"new") => {
+                        verify_len!(":keys new", input.len(), 3);
+
+                        let keys = skip_fail!(keystore.generate(input[2]));
+                        println!("{}: {}", input[2], keys.public);
+                    }
+                    Some(&"list") => {
+                        verify_len!(":keys list", input.len(), 2);
+
+                        let selected = keystore.selected().map(|(name, _)| name);
+                        for (name, pk) in keystore.list() {
+                            let marker = if selected.as_ref() == Some(&name) { "*" } else { " " };
+                            println!("{} {}: {}", marker, name, pk);
+                        }
+                    }
+                    Some(&"import") => {
+                        verify_len!(":keys import", input.len(), 4);
+
+                        let keys = skip_fail!(keystore.import(input[2], input[3]));
+                        println!("{}: {}", input[2], keys.public);
+                    }
+                    Some(&"mnemonic") => {
+                        verify_len!(":keys mnemonic", input.len(), 2);
+
+                        println!("{}", skip_fail!(hd::generate_mnemonic()));
+                    }
+                    Some(&"restore") => {
+                        if input.len() < 5 {
+                            error!("Usage: ':keys restore <name> <index> <phrase>'");
+                            continue;
+                        }
+
+                        let index = skip_fail!(input[3].parse());
+                        let keys = skip_fail!(keystore.restore(input[2], &input[4..].join(" "), index));
+                        println!("{}: {}", input[2], keys.public);
+                    }
+                    Some(&"export") => {
+                        verify_len!(":keys export", input.len(), 3);
+
+                        println!("{}", skip_fail!(keystore.export(input[2])));
+                    }
+                    Some(&"use") => {
+                        verify_len!(":keys use", input.len(), 3);
+
+                        let keys = skip_fail!(keystore.select(input[2]));
+                        println!("Using key '{}': {}", input[2], keys.public);
+                        node.use_keys(keys);
+                    }
+                    _ => println!("Available key commands are: ':keys new <name>, :keys list, :keys import <name> <secret>, :keys export <name>, :keys use <name>, :keys mnemonic, :keys restore <name> <index> <phrase>'"),
+                }
+            }
+            Some(cmd) if cmd.starts_with(':') => {
+                println!("{}", COMMANDS);
             }
             Some(_) => {
-                println!("Available commands are: ':connect <ip:port>, :peers, :balances, :exit, :send <to> <amount>'");
+                skip_fail!(node.chat(&line));
             }
             _ => (),
         }
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+// Domain separation between leaves and inner nodes, so a leaf can never be
+// passed off as an inner node or vice versa.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Decode, Encode, PartialOrd, Ord, Default)]
+pub struct Hash(pub [u8; 32]);
+
+impl Hash {
+    pub fn leaf(data: &[u8]) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_PREFIX]);
+        hasher.update(data);
+        Hash(hasher.finalize().into())
+    }
+
+    fn node(left: &Hash, right: &Hash) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([NODE_PREFIX]);
+        hasher.update(left.0);
+        hasher.update(right.0);
+        Hash(hasher.finalize().into())
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.0[..8] {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A binary Merkle tree over an ordered list of leaves. An unpaired node at
+/// the end of a level is carried up unchanged rather than duplicated, so no
+/// two distinct leaf lists share a root.
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Hash::node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        MerkleTree { levels }
+    }
+
+    /// The root of the tree, the empty tree has the all zero root.
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap().first().copied().unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None
+        }
+
+        let mut siblings = vec![];
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(i ^ 1) {
+                siblings.push(*sibling);
+            }
+            i /= 2;
+        }
+
+        Some(MerkleProof {
+            index: index as u64,
+            leaf_count: self.len() as u64,
+            siblings,
+        })
+    }
+}
+
+/// Proves that a leaf is at `index` in a tree of `leaf_count` leaves.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    pub fn verify(&self, root: &Hash, leaf: &Hash) -> bool {
+        if self.index >= self.leaf_count {
+            return false
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut hash = *leaf;
+        let mut i = self.index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            // The last node of an odd level has no sibling and is carried up.
+            if !(i == width - 1 && width % 2 == 1) {
+                let sibling = match siblings.next() {
+                    Some(s) => s,
+                    None => return false,
+                };
+                hash = if i.is_multiple_of(2) { Hash::node(&hash, sibling) } else { Hash::node(sibling, &hash) };
+            }
+            i /= 2;
+            width = width.div_ceil(2);
+        }
+
+        siblings.next().is_none() && hash == *root
+    }
+}
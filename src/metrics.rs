@@ -0,0 +1,125 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}};
+
+/// Upper bounds of the confirmation latency buckets, in milliseconds.
+const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Default, Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A Prometheus style histogram, where each bucket counts the observations
+/// less than or equal to its bound.
+#[derive(Default, Debug)]
+pub struct Histogram {
+    buckets: [Counter; LATENCY_BUCKETS_MS.len()],
+    sum: Counter,
+    count: Counter,
+}
+
+impl Histogram {
+    pub fn observe(&self, ms: u64) {
+        for (bound, bucket) in LATENCY_BUCKETS_MS.iter().zip(&self.buckets) {
+            if ms <= *bound {
+                bucket.inc();
+            }
+        }
+        self.sum.add(ms);
+        self.count.inc();
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rejection {
+    InvalidSignature,
+    Duplicate,
+    /// The ledger refused the update, e.g. because of a negative fee.
+    Ledger,
+    /// Outside its validity window, either expired or not yet valid.
+    Expired,
+    /// Paying less than `NodeConfig::min_relay_fee` per output.
+    LowFee,
+}
+
+impl Rejection {
+    const ALL: [Rejection; 5] = [Rejection::InvalidSignature, Rejection::Duplicate, Rejection::Ledger, Rejection::Expired, Rejection::LowFee];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Rejection::InvalidSignature => "invalid_signature",
+            Rejection::Duplicate => "duplicate",
+            Rejection::Ledger => "ledger",
+            Rejection::Expired => "expired",
+            Rejection::LowFee => "low_fee",
+        }
+    }
+}
+
+/// Counters kept by a node. Gauges such as the number of peers are read
+/// from the node when rendering instead.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    /// Transactions received from peers or submitted locally.
+    pub trxs_received: Counter,
+    pub trxs_applied: Counter,
+    trxs_rejected: [Counter; Rejection::ALL.len()],
+    /// The time from a transaction being signed until it is applied.
+    pub confirmation_latency: Histogram,
+}
+
+impl Metrics {
+    pub fn reject(&self, reason: Rejection) {
+        self.trxs_rejected[reason as usize].inc();
+    }
+
+    pub fn rejected(&self, reason: Rejection) -> u64 {
+        self.trxs_rejected[reason as usize].get()
+    }
+
+    /// Writes the counters in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        counter(out, "dkk_transactions_received_total", "Transactions received from peers or submitted locally.", self.trxs_received.get());
+        counter(out, "dkk_transactions_applied_total", "Transactions applied to the ledger.", self.trxs_applied.get());
+
+        header(out, "dkk_transactions_rejected_total", "Transactions rejected, by reason.", "counter");
+        for reason in Rejection::ALL {
+            let _ = writeln!(out, "dkk_transactions_rejected_total{{reason=\"{}\"}} {}", reason.label(), self.rejected(reason));
+        }
+
+        let latency = &self.confirmation_latency;
+        header(out, "dkk_confirmation_latency_ms", "Time from a transaction being signed until it is applied.", "histogram");
+        for (bound, bucket) in LATENCY_BUCKETS_MS.iter().zip(&latency.buckets) {
+            let _ = writeln!(out, "dkk_confirmation_latency_ms_bucket{{le=\"{}\"}} {}", bound, bucket.get());
+        }
+        let _ = writeln!(out, "dkk_confirmation_latency_ms_bucket{{le=\"+Inf\"}} {}", latency.count.get());
+        let _ = writeln!(out, "dkk_confirmation_latency_ms_sum {}", latency.sum.get());
+        let _ = writeln!(out, "dkk_confirmation_latency_ms_count {}", latency.count.get());
+    }
+}
+
+pub fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+pub fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -1,21 +1,44 @@
-use std::{net::SocketAddr, hash::{Hasher, Hash}, io, fmt};
+use std::{net::SocketAddr, hash::{Hasher, Hash}, io, fmt, sync::Arc, time::Duration};
 
-use bincode::{decode_from_slice, encode_into_slice, Encode};
 use tokio::{sync::mpsc::{Sender, channel, Receiver}, net::{TcpStream, tcp::{OwnedWriteHalf, OwnedReadHalf}}, io::AsyncWriteExt};
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
-use crate::{types::*, macros::*};
+use anyhow::anyhow;
+use bincode::config::Config;
+
+use crate::{types::*, log_fail, metrics::Counter};
+
+/// How long the other end of a new connection has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest frame accepted, anything larger is assumed to be garbage.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// The configuration anything received from peers is decoded with. Bincode
+/// allocates whatever length a string or collection claims before reading
+/// it, so without a limit a few bytes could make us allocate terabytes.
+pub fn decode_config() -> impl Config {
+    bincode::config::standard().with_limit::<{ MAX_FRAME_LEN as usize }>()
+}
 
 #[derive(Clone)]
 pub struct Peer {
     node_name: NodeName,
     address: SocketAddr,
+    features: Features,
+    /// Bytes read from and written to the connection, including framing.
+    bytes_in: Arc<Counter>,
+    bytes_out: Arc<Counter>,
     peer: Sender<Packet>,
     node: Sender<NodeRequest>,
 }
 
 impl Peer {
-    pub async fn new(tx_node: Sender<NodeRequest>, stream: TcpStream, node_name: NodeName) -> anyhow::Result<Self> {
+    pub async fn new(tx_node: Sender<NodeRequest>, mut stream: TcpStream, node_name: NodeName, handshake: &Handshake) -> anyhow::Result<Self> {
+        let theirs = tokio::time::timeout(HANDSHAKE_TIMEOUT, Self::handshake(&mut stream, handshake))
+            .await
+            .map_err(|_| anyhow!("Handshake with {} timed out", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default()))??;
+
         // Create a mpsc channel for managing writes.
         // TODO: Don't use magic numbers, use magic consts
         let (tx_peer, rx_peer) = channel::<Packet>(1000);
@@ -25,6 +48,9 @@ impl Peer {
         let conn = Self {
             node_name: node_name.clone(),
             address: read_stream.peer_addr()?,
+            features: theirs.features,
+            bytes_in: Arc::new(Counter::default()),
+            bytes_out: Arc::new(Counter::default()),
             node: tx_node.clone(),
             peer: tx_peer.clone(),
         };
@@ -48,6 +74,65 @@ impl Peer {
         Ok(conn)
     }
 
+    /// Exchanges handshakes with the other end of the stream, failing if
+    /// they are on another network or speak an unsupported protocol version.
+    async fn handshake(stream: &mut TcpStream, ours: &Handshake) -> anyhow::Result<Handshake> {
+        let bytes = bincode::encode_to_vec(ours, bincode::config::standard())?;
+        Self::write_frame(stream, &bytes).await?;
+
+        let bytes = Self::read_frame(stream).await?.ok_or_else(|| anyhow!("Connection closed during handshake"))?;
+        let (theirs, _): (Handshake, _) = bincode::decode_from_slice(&bytes, decode_config())?;
+        theirs.check(ours)?;
+
+        Ok(theirs)
+    }
+
+    /// Frames are a big endian u32 length followed by that many bytes.
+    async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), bytes: &[u8]) -> anyhow::Result<()> {
+        let len = u32::try_from(bytes.len()).ok().filter(|len| *len <= MAX_FRAME_LEN)
+            .ok_or_else(|| anyhow!("Frame of {} bytes is too large", bytes.len()))?;
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream.write_all(bytes).await?;
+
+        Ok(())
+    }
+
+    /// Returns `None` if the stream was closed between frames.
+    async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Err(anyhow!("Frame of {} bytes is too large", len))
+        }
+        let mut bytes = vec![0u8; len as usize];
+        stream.read_exact(&mut bytes).await?;
+
+        Ok(Some(bytes))
+    }
+
+    pub fn get_features(&self) -> Features {
+        self.features
+    }
+
+    pub fn get_bytes_in(&self) -> u64 {
+        self.bytes_in.get()
+    }
+
+    pub fn get_bytes_out(&self) -> u64 {
+        self.bytes_out.get()
+    }
+
+    /// The number of packets waiting to be written to the connection.
+    pub fn queue_len(&self) -> usize {
+        self.peer.max_capacity() - self.peer.capacity()
+    }
+
     pub async fn send(&self, packet: Packet) {
         log_fail!(self.peer.send(packet).await)
     }
@@ -57,64 +142,50 @@ impl Peer {
     }
 
     async fn request_handler(self, mut stream: OwnedWriteHalf, mut rx: Receiver<Packet>) {
-        loop {
-            match rx.recv().await {
-                Some(req) => {
-                    log_fail!(Self::send_internal(&mut stream, &req, &self.node_name).await)
-                }
-                None => break,
-            }
+        while let Some(req) = rx.recv().await {
+            log_fail!(self.send_internal(&mut stream, &req).await)
         }
     }
 
-    async fn send_internal(write_stream: &mut OwnedWriteHalf, packet: &Packet, node_name: &NodeName) -> anyhow::Result<()> {
+    async fn send_internal(&self, write_stream: &mut OwnedWriteHalf, packet: &Packet) -> anyhow::Result<()> {
         let bytes = bincode::encode_to_vec(packet, bincode::config::standard())?;
-        write_stream.write_all(&bytes).await?;
+        Self::write_frame(write_stream, &bytes).await?;
+        self.bytes_out.add(bytes.len() as u64 + 4);
         let to = write_stream.peer_addr()?;
 
-        info!("󰁜 {:#?}: {:?} - ({:#?}b -> {:#})", node_name, packet, bytes.len(), to);
+        info!("󰁜 {:#?}: {:?} - ({:#?}b -> {:#})", self.node_name, packet, bytes.len(), to);
 
         Ok(())
     }
 
     async fn listen(self, mut read_stream: OwnedReadHalf, node_name: NodeName) {
         loop {
-            let mut buf = [0; 4096];
-
-            match read_stream.read(&mut buf).await {
-                Ok(0) => {
-                    trace!("0 bytes read, closing connection");
-                    break;
+            let bytes = match Self::read_frame(&mut read_stream).await {
+                Ok(Some(bytes)) => {
+                    self.bytes_in.add(bytes.len() as u64 + 4);
+                    bytes
                 }
-                // TODO: Remove unwraps and handle buf more elegantly
-                Ok(bytes_read) => {
-                    tokio::spawn({
-                        let node_name = node_name.clone();
-                        let peer = self.clone();
-                        let bytes_read = bytes_read.clone();
-                        let mut buf: Vec<u8> = buf.clone().into_iter().take(bytes_read).collect();
-                        async move {
-                            loop {
-                                let (packet, bytes_decoded): (Packet, _) = bincode::decode_from_slice(
-                                    &buf, 
-                                    bincode::config::standard()
-                                ).unwrap();
-
-                                info!("󰁂 {:?}: {:?} - ({:#?}/{:#?}b)", node_name, packet, bytes_decoded, bytes_read);
-                                peer.node.send((packet, peer.clone())).await.unwrap();
-
-                                if bytes_read != bytes_decoded && buf.len() - bytes_decoded != 0 {
-                                    buf = buf.into_iter().skip(bytes_decoded).collect();
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                    });
+                Ok(None) => {
+                    trace!("Connection closed by {:?}", self);
+                    break;
                 }
                 Err(e) => {
-                    error!("{:?}", e);
+                    error!("{:?}: Closing connection to {:?}: {}", node_name, self, e);
+                    break;
+                }
+            };
+
+            // Packets are framed, so a packet we can't decode, most likely a
+            // variant added in a later protocol version, can be skipped. The
+            // decode limit keeps it from claiming more memory than the frame.
+            match bincode::decode_from_slice::<Packet, _>(&bytes, decode_config()) {
+                Ok((packet, _)) => {
+                    info!("󰁂 {:?}: {:?} - ({:#?}b)", node_name, packet, bytes.len());
+                    if self.node.send((packet, self.clone())).await.is_err() {
+                        break;
+                    }
                 }
+                Err(e) => debug!("{:?}: Skipping undecodable packet from {:?}: {}", node_name, self, e),
             }
         }
     }
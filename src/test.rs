@@ -3,7 +3,11 @@ mod tests {
     use std::time::Duration;
     use tokio::time::sleep;
 
-    use crate::{client::*, types::{AccountTransaction, Amount, Timestamp}};
+    use std::{collections::HashSet, str::FromStr};
+    use proptest::prelude::*;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+    use crate::{address::*, bench::*, client::*, genesis::*, hd::*, keystore::*, merkle::*, metrics::Rejection, peer::decode_config, types::{AccountCreation, Authorization, ChatMessage, MAX_CHAT_LEN, AccountDiff, AccountTransaction, AssetId, EscrowRelease, Lock, Output, Amount, KeyPair, Direction, Features, Handshake, Packet, FEE_POOL, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, History, HistoryFilter, Id, Ledger, LedgerSnapshot, MultisigPolicy, SignedAccountTransaction, AssetIssuance, Timestamp, BalanceProof, Inclusion}};
     use log::info;
 
     fn log_init() {
@@ -14,6 +18,921 @@ mod tests {
     const _MID:   Duration = Duration::from_millis(1000);
     const _LONG:  Duration = Duration::from_millis(2000);
 
+    /// A transfer between the accounts of `funded_ledger`, possibly invalid.
+    #[derive(Clone, Debug)]
+    struct Transfer {
+        from: usize,
+        to: usize,
+        amount: i64,
+        fee: i64,
+        bad_signature: bool,
+    }
+
+    const FUNDED_ACCOUNTS: usize = 4;
+    const FUNDED_BALANCE: i64 = 100;
+
+    fn transfer() -> impl Strategy<Value = Transfer> {
+        (0..FUNDED_ACCOUNTS, 0..FUNDED_ACCOUNTS, -5..60i64, -1..3i64, prop::bool::weighted(0.1))
+            .prop_map(|(from, to, amount, fee, bad_signature)| Transfer { from, to, amount, fee, bad_signature })
+    }
+
+    fn valid_transfer() -> impl Strategy<Value = Transfer> {
+        transfer().prop_map(|t| Transfer { amount: t.amount.abs() + 1, fee: t.fee.abs(), bad_signature: false, ..t })
+    }
+
+    fn funded_ledger() -> (Vec<KeyPair>, Ledger) {
+        // Deriving keys from the mnemonic is slow, and this runs for every case.
+        static KEYS: std::sync::OnceLock<Vec<KeyPair>> = std::sync::OnceLock::new();
+        let keys = KEYS.get_or_init(|| genesis_accounts(FUNDED_ACCOUNTS as u32).unwrap()).clone();
+        let genesis = Genesis {
+            balances: keys.iter().map(|k| (k.public, Amount(FUNDED_BALANCE))).collect(),
+            ..Genesis::dev()
+        };
+        (keys, Ledger::from_genesis(&genesis))
+    }
+
+    /// A DKK transfer without a fee, lock, outputs or validity window, timestamped now.
+    fn dkk_transfer(from: Id, to: Id, amount: i64) -> AccountTransaction {
+        AccountTransaction {
+            from,
+            to,
+            asset: AssetId::DKK,
+            amount: Amount(amount),
+            fee: Amount(0),
+            lock: None,
+            outputs: vec![],
+            valid_after: None,
+            valid_until: None,
+            timestamp: Timestamp::since_unix().unwrap(),
+        }
+    }
+
+    /// Signs the transfers, timestamping the i'th at `time(i)`.
+    fn sign_transfers(keys: &[KeyPair], transfers: &[Transfer], time: fn(usize) -> u64) -> Vec<SignedAccountTransaction> {
+        transfers.iter().enumerate().map(|(i, t)| {
+            let trx = AccountTransaction {
+                fee: Amount(t.fee),
+                timestamp: Timestamp::from_millis(time(i)),
+                ..dkk_transfer(keys[t.from].public, keys[t.to].public, t.amount)
+            };
+            let signer = if t.bad_signature { &keys[(t.from + 1) % keys.len()] } else { &keys[t.from] };
+            signer.private.sign(trx).unwrap()
+        }).collect()
+    }
+
+    /// Applies a transaction as a node would once transactions are totally
+    /// ordered, returning whether it was accepted.
+    fn apply_ordered(ledger: &Ledger, history: &History, trx: &SignedAccountTransaction) -> bool {
+        trx.verify() && !history.contains(&trx.trx) && ledger.update_funded(&trx.trx).is_ok() && history.insert(trx.trx.clone())
+    }
+
+    /// Applies a transaction as a node does today, see `Node::broadcast`,
+    /// returning whether it was accepted.
+    fn apply_unordered(ledger: &Ledger, history: &History, trx: &SignedAccountTransaction) -> bool {
+        if !trx.verify() || !history.insert(trx.trx.clone()) {
+            return false
+        }
+        let applied = ledger.update(&trx.trx).is_ok();
+        if !applied {
+            history.remove(&trx.trx);
+        }
+        applied
+    }
+
+    proptest! {
+        #[test]
+        fn ledger_invariants(
+            transfers in prop::collection::vec(transfer(), 0..30),
+            replays in prop::collection::vec(any::<prop::sample::Index>(), 0..5),
+        ) {
+            let (keys, ledger) = funded_ledger();
+            let (_, unordered) = funded_ledger();
+            let (history, unordered_history) = (History::default(), History::default());
+            let supply = ledger.total_supply();
+
+            // Distinct timestamps keep identical transfers apart.
+            let signed = sign_transfers(&keys, &transfers, |i| i as u64);
+            let mut deliveries: Vec<usize> = (0..signed.len()).collect();
+            if !signed.is_empty() {
+                deliveries.extend(replays.iter().map(|i| i.index(signed.len())));
+            }
+
+            // Every class of invalid transaction is rejected, and everything else accepted.
+            let mut balances = [FUNDED_BALANCE; FUNDED_ACCOUNTS];
+            let mut accepted = HashSet::new();
+            for i in deliveries {
+                let t = &transfers[i];
+                // Until transactions are ordered, overdrafts are accepted.
+                let well_formed = !t.bad_signature && t.amount > 0 && t.fee >= 0;
+                let new = !unordered_history.contains(&signed[i].trx);
+                prop_assert_eq!(apply_unordered(&unordered, &unordered_history, &signed[i]), well_formed && new, "{:?}", t);
+
+                let expected = well_formed
+                    && !accepted.contains(&i)
+                    && balances[t.from] >= t.amount + t.fee;
+                prop_assert_eq!(apply_ordered(&ledger, &history, &signed[i]), expected, "{:?}", t);
+
+                if expected {
+                    accepted.insert(i);
+                    balances[t.from] -= t.amount + t.fee;
+                    balances[t.to] += t.amount;
+                }
+            }
+
+            prop_assert!(ledger.check_supply(supply).is_ok());
+            prop_assert!(ledger.snapshot().iter().all(|(_, amount)| amount.0 >= 0));
+            prop_assert!(unordered.check_supply(supply).is_ok());
+        }
+
+        #[test]
+        fn delivery_order_does_not_matter(
+            (transfers, order) in prop::collection::vec(valid_transfer(), 0..30)
+                .prop_flat_map(|t| { let n = t.len(); (Just(t), Just((0..n).collect::<Vec<_>>()).prop_shuffle()) }),
+        ) {
+            let (keys, _) = funded_ledger();
+            // Few distinct timestamps, so ordering has to break ties.
+            let signed = sign_transfers(&keys, &transfers, |i| (i % 3) as u64);
+
+            // Without a total order balances may go negative, but every
+            // ledger still ends up the same.
+            let (_, in_order) = funded_ledger();
+            let (_, shuffled) = funded_ledger();
+            for trx in &signed {
+                in_order.update(&trx.trx).unwrap();
+            }
+            for &i in &order {
+                shuffled.update(&signed[i].trx).unwrap();
+            }
+            prop_assert_eq!(in_order.state_root(), shuffled.state_root());
+
+            // Histories order what they are given the same, so once they are
+            // applied in that order, overdrafts are rejected consistently too.
+            let (h1, h2) = (History::default(), History::default());
+            for trx in &signed {
+                h1.insert(trx.trx.clone());
+            }
+            for &i in &order {
+                h2.insert(signed[i].trx.clone());
+            }
+            prop_assert_eq!(h1.ordered(), h2.ordered());
+
+            let (_, ledger) = funded_ledger();
+            let supply = ledger.total_supply();
+            for trx in h2.ordered() {
+                let _ = ledger.update_funded(&trx);
+            }
+            prop_assert!(ledger.check_supply(supply).is_ok());
+            prop_assert!(ledger.snapshot().iter().all(|(_, amount)| amount.0 >= 0));
+        }
+
+        #[test]
+        fn batch_verification_agrees(transfers in prop::collection::vec(transfer(), 0..10), small_order in prop::option::of(any::<prop::sample::Index>())) {
+            let (keys, _) = funded_ledger();
+            let mut signed = sign_transfers(&keys, &transfers, |i| i as u64);
+            // Batch verification alone would accept a small order R.
+            if let Some(trx) = small_order.and_then(|i| signed.get_mut(i.index(transfers.len().max(1)))) {
+                let mut bytes = match trx.auth { Authorization::Single(signature) => ed25519_dalek::Signature::from(signature).to_bytes(), _ => unreachable!() };
+                bytes[..32].fill(0);
+                bytes[0] = 1;
+                trx.auth = Authorization::Single(ed25519_dalek::Signature::from_bytes(&bytes).into());
+            }
+
+            let first_invalid = signed.iter().position(|trx| !trx.verify());
+            prop_assert_eq!(SignedAccountTransaction::verify_batch(&signed), first_invalid.map_or(Ok(()), Err));
+        }
+    }
+
+    #[tokio::test]
+    async fn light_node_verifies_balances() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::new("NodeA").await?;
+        let node_b = Node::new("NodeB").await?;
+        let light = Node::with_config("Light", NodeConfig { light: true, ..Default::default() }).await?;
+        let keys = genesis_accounts(2)?;
+
+        node_a.connect(node_b.get_address()).await?;
+        light.connect(node_a.get_address()).await?;
+        light.connect(node_b.get_address()).await?;
+
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 42);
+        node_a.send(keys[0].private.sign(trx)?).await;
+        sleep(SHORT).await;
+
+        assert_eq!(light.verified_balance(&keys[1].public).await?, Amount(42));
+        assert_eq!(light.verified_balance(&keys[0].public).await?, Amount(-42));
+        assert_eq!(light.get_ledger().len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn byzantine_peers() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::new("NodeA").await?;
+        let node_b = Node::new("NodeB").await?;
+        let liar = Node::with_config("Liar", NodeConfig { behaviour: Behaviour::Lying, ..Default::default() }).await?;
+        let spammer = Node::with_config("Spammer", NodeConfig { behaviour: Behaviour::Spammer, ..Default::default() }).await?;
+        let light = Node::with_config("Light", NodeConfig { light: true, ..Default::default() }).await?;
+        let keys = genesis_accounts(2)?;
+
+        node_a.connect(node_b.get_address()).await?;
+        liar.connect(node_a.get_address()).await?;
+        spammer.connect(node_a.get_address()).await?;
+        for node in [&node_a, &node_b, &liar] {
+            light.connect(node.get_address()).await?;
+        }
+
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 42);
+        node_a.send(keys[0].private.sign(trx)?).await;
+        sleep(SHORT).await;
+
+        // The liar is outvoted, and the spam is rejected without touching the ledger.
+        assert_eq!(light.verified_balance(&keys[1].public).await?, Amount(42));
+        assert!(node_a.metrics.rejected(Rejection::InvalidSignature) > 0);
+        assert_eq!(node_a.get_ledger().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_with_peer() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::new("NodeA").await?;
+        let node_b = Node::new("NodeB").await?;
+        node_a.connect(node_b.get_address()).await?;
+        assert!(node_a.compare(node_b.get_address()).await?.is_empty());
+
+        // Only B learns of the transaction.
+        let keys = genesis_accounts(2)?;
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 10);
+        node_b.state.ledger.update(&trx)?;
+        node_b.state.history.insert(trx.clone());
+
+        let divergence = node_a.compare(node_b.get_address()).await?;
+        let mut expected = vec![
+            AccountDiff { id: keys[0].public, asset: AssetId::DKK, ours: None, theirs: Some(Amount(-10)) },
+            AccountDiff { id: keys[1].public, asset: AssetId::DKK, ours: None, theirs: Some(Amount(10)) },
+        ];
+        expected.sort_by_key(|diff| diff.id);
+        assert_eq!(divergence.accounts, expected);
+        assert_eq!(divergence.first_difference, Some((None, Some(trx))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nodes_on_different_chains_refuse_to_connect() -> anyhow::Result<()> {
+        log_init();
+
+        let testnet = NodeConfig { genesis: Genesis::testnet()?, ..Default::default() };
+        let node_a = Node::with_config("NodeA", testnet.clone()).await?;
+        let node_b = Node::with_config("NodeB", testnet).await?;
+        let node_c = Node::new("NodeC").await?;
+
+        let keys = genesis_accounts(1)?.remove(0);
+        assert_eq!(node_a.get_balance(&keys.public), Amount(1_000_000));
+        node_a.check_supply()?;
+
+        node_a.connect(node_b.get_address()).await?;
+        assert!(node_a.connect(node_c.get_address()).await.is_err());
+        sleep(SHORT).await;
+
+        assert!(node_a.get_peers().contains(&node_b.get_address()));
+        assert!(!node_a.get_peers().contains(&node_c.get_address()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn account_registration() -> anyhow::Result<()> {
+        log_init();
+
+        let mut genesis = Genesis::testnet()?;
+        genesis.consensus.accounts = AccountRules { implicit_registration: false, registration_fee: Amount(1), min_deposit: Amount(5) };
+        let supply = Ledger::from_genesis(&genesis).total_supply();
+        let config = NodeConfig { genesis: genesis.clone(), ..Default::default() };
+        let node_a = Node::with_config("NodeA", config.clone()).await?;
+        let node_b = Node::with_config("NodeB", config).await?;
+        node_a.connect(node_b.get_address()).await?;
+
+        let keys = genesis_accounts(1)?.remove(0);
+        let new_keys = node_a.gen_keys();
+        let new = new_keys.public;
+        let trx = |amount: i64| AccountTransaction { timestamp: Timestamp::from_millis(1), ..dkk_transfer(keys.public, new, amount) };
+        assert!(node_a.get_ledger().update(&trx(10)).is_err());
+
+        node_a.use_keys(keys.clone());
+        node_a.create_account(&new_keys, Amount(4), None).await?;
+        assert_eq!(node_a.get_ledger().account(&new), None);
+        node_a.create_account(&new_keys, Amount(5), Some("savings".to_owned())).await?;
+        sleep(SHORT).await;
+
+        for node in [&node_a, &node_b] {
+            let ledger = node.get_ledger();
+            assert_eq!(ledger.account(&new).and_then(|info| info.label), Some("savings".to_owned()));
+            assert_eq!(ledger.get(&new), Some(Amount(5)));
+            assert_eq!(ledger.get(&keys.public), Some(Amount(1_000_000 - 6)));
+            assert_eq!(ledger.get(&FEE_POOL), Some(Amount(1)));
+            ledger.check_supply(supply)?;
+        }
+        let again = AccountCreation { id: new, from: keys.public, deposit: Amount(5), fee: Amount(1), label: None, timestamp: Timestamp::from_millis(2) };
+        assert!(node_a.get_ledger().register(&again).is_err());
+
+        // Registering someone else's account needs their signature.
+        let other = node_a.gen_keys();
+        let creation = AccountCreation { id: other.public, ..again };
+        assert!(!keys.private.sign_creation(creation.clone(), &keys.private)?.verify());
+        assert!(keys.private.sign_creation(creation.clone(), &other.private)?.verify());
+        node_a.get_ledger().update(&trx(10))?;
+
+        // Multisig accounts have no key of their own, so enough of their signers consent.
+        let policy = MultisigPolicy::new(2, vec![keys.public, new, other.public])?;
+        let creation = AccountCreation { id: policy.id(), ..creation };
+        assert!(!keys.private.sign_multisig_creation(creation.clone(), policy.clone(), &[&other.private])?.verify());
+        let signed = keys.private.sign_multisig_creation(creation, policy.clone(), &[&other.private, &new_keys.private])?;
+        assert!(signed.verify());
+        node_a.get_ledger().register(&signed.creation)?;
+        node_a.get_ledger().update(&AccountTransaction { to: policy.id(), ..trx(10) })?;
+
+        // With implicit registration, the sender pays the fee of the recipient.
+        genesis.consensus.accounts.implicit_registration = true;
+        let ledger = Ledger::from_genesis(&genesis);
+        ledger.update(&trx(10))?;
+        assert_eq!(ledger.get(&keys.public), Some(Amount(1_000_000 - 11)));
+        assert_eq!(ledger.account(&new).map(|info| info.created), Some(Timestamp::from_millis(1)));
+        ledger.check_supply(supply)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_issued_assets() -> anyhow::Result<()> {
+        log_init();
+
+        let config = NodeConfig { genesis: Genesis::testnet()?, ..Default::default() };
+        let supply = config.genesis.total_supply().unwrap();
+        let node_a = Node::with_config("NodeA", config.clone()).await?;
+        let node_b = Node::with_config("NodeB", config).await?;
+        node_a.connect(node_b.get_address()).await?;
+
+        let keys = genesis_accounts(2)?;
+        node_a.use_keys(keys[0].clone());
+        let coin = node_a.issue_asset("COIN", Amount(100), Amount(60)).await?;
+        assert_eq!(coin, AssetId::new(&keys[0].public, "COIN"));
+        assert_eq!(AssetId::from_str(&coin.to_string())?, coin);
+        assert_eq!(AssetId::from_str("DKK")?, AssetId::DKK);
+
+        // Over the cap, or with another cap, nothing is issued.
+        node_a.issue_asset("COIN", Amount(100), Amount(50)).await?;
+        node_a.issue_asset("COIN", Amount(200), Amount(10)).await?;
+        node_a.transfer_asset(keys[1].public, coin, Amount(25)).await?;
+        sleep(SHORT).await;
+
+        for node in [&node_a, &node_b] {
+            let ledger = node.get_ledger();
+            assert_eq!(ledger.asset(&coin).map(|asset| asset.issued), Some(Amount(60)));
+            assert_eq!(node.get_asset_balance(&keys[0].public, &coin), Amount(35));
+            assert_eq!(node.get_asset_balance(&keys[1].public, &coin), Amount(25));
+            // The balance next to a transfer is in the asset transferred.
+            let history = node.get_balance_history(&keys[0].public, &HistoryFilter::default());
+            assert_eq!(history.iter().map(|(_, b)| *b).collect::<Vec<_>>(), vec![Some(Amount(35))]);
+            assert_eq!(ledger.snapshot().asset_supply(&coin), Amount(60));
+            ledger.check_supply(supply)?;
+        }
+
+        let ledger = node_b.get_ledger();
+        let proof = ledger.prove_asset_balance(&keys[1].public, &coin);
+        assert_eq!(proof.verify_asset(&ledger.state_root(), &keys[1].public, &coin), Some(Amount(25)));
+        assert_eq!(proof.verify(&ledger.state_root(), &keys[1].public), None);
+
+        let unknown = AccountTransaction {
+            asset: AssetId::new(&keys[1].public, "COIN"),
+            ..dkk_transfer(keys[0].public, keys[1].public, 1)
+        };
+        assert!(ledger.update(&unknown).is_err());
+
+        // Issuances whose sum overflows the cap are rejected.
+        let mut big = AssetIssuance {
+            issuer: keys[1].public,
+            symbol: "BIG".to_owned(),
+            cap: Amount(i64::MAX),
+            amount: Amount(i64::MAX),
+            timestamp: Timestamp::since_unix()?,
+        };
+        ledger.issue(&big)?;
+        big.timestamp = Timestamp::from_millis(big.timestamp.as_millis() + 1);
+        assert!(ledger.issue(&big).is_err());
+        assert_eq!(ledger.asset(&big.asset()).map(|asset| asset.issued), Some(Amount(i64::MAX)));
+
+        // Nor may an issuance overflow the issuer's balance, which overdrafts
+        // elsewhere can push past what was issued.
+        let mut more = AssetIssuance { symbol: "MORE".to_owned(), amount: Amount(1), ..big.clone() };
+        ledger.issue(&more)?;
+        ledger.update(&AccountTransaction { asset: more.asset(), amount: Amount(i64::MAX - 1), ..unknown.clone() })?;
+        more.timestamp = Timestamp::from_millis(more.timestamp.as_millis() + 1);
+        assert!(ledger.issue(&more).is_err());
+        assert_eq!(ledger.get_asset(&keys[1].public, &more.asset()), Some(Amount(i64::MAX)));
+        assert_eq!(ledger.asset(&more.asset()).map(|asset| asset.issued), Some(Amount(1)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn escrows() -> anyhow::Result<()> {
+        use sha2::{Digest, Sha256};
+        log_init();
+
+        let config = NodeConfig { genesis: Genesis::testnet()?, ..Default::default() };
+        let supply = config.genesis.total_supply().unwrap();
+        let node_a = Node::with_config("NodeA", config.clone()).await?;
+        let node_b = Node::with_config("NodeB", config).await?;
+        node_a.connect(node_b.get_address()).await?;
+
+        let keys = genesis_accounts(2)?;
+        let now = Timestamp::since_unix()?.as_millis();
+        let later = Timestamp::from_millis(now + 60_000);
+        node_a.use_keys(keys[0].clone());
+        let timed = node_a.transfer_locked(keys[1].public, AssetId::DKK, Amount(100), Lock::Time(later)).await?;
+        let hash = Sha256::digest(b"secret").into();
+        let hashed = node_a.transfer_locked(keys[1].public, AssetId::DKK, Amount(50), Lock::Hash { hash, deadline: later }).await?;
+        sleep(SHORT).await;
+
+        assert!(node_a.release(timed, None).is_err());
+        assert!(node_a.release(hashed, None).is_err());
+        assert!(node_a.release(hashed, Some(b"guess".to_vec())).is_err());
+        assert_eq!(node_a.release(hashed, Some(b"secret".to_vec()))?, keys[1].public);
+        sleep(SHORT).await;
+
+        for node in [&node_a, &node_b] {
+            let ledger = node.get_ledger();
+            assert_eq!(ledger.get(&timed), Some(Amount(100)));
+            assert_eq!(ledger.get(&hashed), Some(Amount(0)));
+            assert_eq!(ledger.escrow(&hashed), None);
+            assert_eq!(ledger.get(&keys[0].public), Some(Amount(1_000_000 - 150)));
+            assert_eq!(ledger.get(&keys[1].public), Some(Amount(1_000_000 + 50)));
+            ledger.check_supply(supply)?;
+        }
+
+        // Past the deadline, the time lock pays out and the hash lock refunds.
+        let ledger = node_a.get_ledger();
+        let release = |escrow, preimage: Option<&[u8]>, timestamp| EscrowRelease { escrow, preimage: preimage.map(|p| p.to_vec()), timestamp };
+        assert_eq!(ledger.release(&release(timed, None, later))?, keys[1].public);
+        let refunded = node_a.transfer_locked(keys[1].public, AssetId::DKK, Amount(7), Lock::Hash { hash, deadline: later }).await?;
+        assert!(ledger.release(&release(refunded, Some(b"secret"), later)).is_err());
+        assert_eq!(ledger.release(&release(refunded, None, later))?, keys[0].public);
+        assert!(ledger.release(&release(refunded, None, later)).is_err());
+        assert_eq!(ledger.get(&keys[0].public), Some(Amount(1_000_000 - 150)));
+        ledger.check_supply(supply)?;
+
+        // Overdrafts are allowed, but a release overflowing the recipient's balance isn't.
+        let overdrawn = Ledger::from_genesis(&Genesis::testnet()?);
+        let huge = AccountTransaction {
+            lock: Some(Lock::Time(Timestamp::from_millis(0))),
+            ..dkk_transfer(keys[0].public, keys[1].public, i64::MAX)
+        };
+        overdrawn.update(&huge)?;
+        assert!(overdrawn.release(&release(huge.escrow_id(), None, later)).is_err());
+        assert!(overdrawn.escrow(&huge.escrow_id()).is_some());
+        assert_eq!(overdrawn.get(&keys[1].public), Some(Amount(1_000_000)));
+
+        // A claim backdated to before the deadline is refused once it has passed.
+        let now = Timestamp::since_unix()?;
+        let soon = Timestamp::from_millis(now.as_millis() + 100);
+        let late = node_a.transfer_locked(keys[1].public, AssetId::DKK, Amount(3), Lock::Hash { hash, deadline: soon }).await?;
+        sleep(SHORT).await;
+        node_b.receive_release(release(late, Some(b"secret"), Timestamp::from_millis(now.as_millis() - 60_000)), node_a.get_address());
+        assert!(node_b.get_ledger().escrow(&late).is_some());
+
+        // A release arriving before its lock is applied once the lock arrives.
+        let node_c = Node::with_config("NodeC", NodeConfig { genesis: Genesis::testnet()?, ..Default::default() }).await?;
+        let trx = AccountTransaction {
+            lock: Some(Lock::Hash { hash, deadline: later }),
+            ..dkk_transfer(keys[0].public, keys[1].public, 5)
+        };
+        let early = trx.escrow_id();
+        let peer = node_a.get_address();
+        node_c.receive_release(release(early, Some(b"secret"), Timestamp::since_unix()?), peer);
+        // Another release of the same escrow doesn't replace the first.
+        node_c.receive_release(release(early, None, Timestamp::since_unix()?), peer);
+        node_c.send(keys[0].private.sign(trx)?).await;
+        sleep(SHORT).await;
+        let ledger = node_c.get_ledger();
+        assert_eq!(ledger.escrow(&early), None);
+        assert_eq!(ledger.get(&keys[1].public), Some(Amount(1_000_000 + 5)));
+
+        // Echoes of applied releases aren't held back, and each peer may only hold back so many.
+        node_c.receive_release(release(early, Some(b"secret"), Timestamp::since_unix()?), peer);
+        assert!(node_c.pending_releases.is_empty());
+        for i in 0..200 {
+            node_c.receive_release(release(Id::from([i; 32]), None, Timestamp::since_unix()?), peer);
+        }
+        node_c.receive_release(release(Id::from([255; 32]), None, Timestamp::since_unix()?), node_b.get_address());
+        assert_eq!(node_c.pending_releases.len(), 101);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batched_outputs() -> anyhow::Result<()> {
+        log_init();
+
+        let genesis = Genesis::testnet()?;
+        let supply = genesis.total_supply().unwrap();
+        let node_a = Node::with_config("NodeA", NodeConfig { genesis: genesis.clone(), min_relay_fee: Amount(2), ..Default::default() }).await?;
+        let node_b = Node::with_config("NodeB", NodeConfig { genesis: genesis.clone(), ..Default::default() }).await?;
+        node_a.connect(node_b.get_address()).await?;
+
+        let keys = genesis_accounts(1)?.remove(0);
+        let to: Vec<Id> = (0..3).map(|_| node_a.gen_keys().public).collect();
+        node_a.use_keys(keys.clone());
+        let outputs = vec![
+            Output { to: to[0], amount: Amount(10) },
+            Output { to: to[1], amount: Amount(20) },
+            Output { to: to[0], amount: Amount(5) },
+        ];
+        node_a.transfer_batch(AssetId::DKK, outputs).await?;
+        sleep(SHORT).await;
+
+        for node in [&node_a, &node_b] {
+            assert_eq!(node.get_balance(&to[0]), Amount(15));
+            assert_eq!(node.get_balance(&to[1]), Amount(20));
+            // Fees scale with the number of outputs.
+            assert_eq!(node.get_balance(&keys.public), Amount(1_000_000 - 35 - 6));
+            let balances = |id: &Id| node.get_balance_history(id, &HistoryFilter::default()).into_iter().map(|(_, b)| b).collect::<Vec<_>>();
+            assert_eq!(balances(&to[0]), vec![Some(Amount(15))]);
+            // Including the fee.
+            assert_eq!(balances(&keys.public), vec![Some(Amount(1_000_000 - 35 - 6))]);
+            node.check_supply()?;
+        }
+        assert_eq!(node_a.metrics.rejected(Rejection::LowFee), 0);
+
+        // An invalid output spoils the whole batch.
+        let ledger = Ledger::from_genesis(&genesis);
+        let mut trx = AccountTransaction {
+            outputs: vec![Output { to: to[1], amount: Amount(0) }],
+            ..dkk_transfer(keys.public, to[2], 10)
+        };
+        assert!(ledger.update(&trx).is_err());
+        trx.outputs[0].amount = Amount(1_000_000);
+        assert!(ledger.update_funded(&trx).is_err());
+        assert_eq!(ledger.get(&to[2]), None);
+        trx.outputs[0].amount = Amount(1);
+        ledger.update_funded(&trx)?;
+        assert_eq!(ledger.get(&to[1]), Some(Amount(1)));
+        ledger.check_supply(supply)?;
+
+        // Outputs whose sum overflows are rejected rather than panicking.
+        trx.amount = Amount(i64::MAX);
+        trx.outputs[0].amount = Amount(i64::MAX);
+        assert!(ledger.update(&trx).is_err());
+        assert!(ledger.update_funded(&trx).is_err());
+        assert_eq!(ledger.get(&to[2]), Some(Amount(10)));
+        ledger.check_supply(supply)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validity_windows() -> anyhow::Result<()> {
+        log_init();
+
+        let config = NodeConfig { genesis: Genesis::testnet()?, ..Default::default() };
+        let node_a = Node::with_config("NodeA", config.clone()).await?;
+        let node_b = Node::with_config("NodeB", config).await?;
+        node_a.connect(node_b.get_address()).await?;
+
+        let keys = genesis_accounts(2)?;
+        let now = Timestamp::since_unix()?.as_millis();
+        let trx = AccountTransaction {
+            valid_until: Some(Timestamp::from_millis(now - 1000)),
+            timestamp: Timestamp::from_millis(now - 2000),
+            ..dkk_transfer(keys[0].public, keys[1].public, 1)
+        };
+        let early = AccountTransaction { valid_after: Some(Timestamp::from_millis(now + 60_000)), valid_until: None, ..trx.clone() };
+        let current = AccountTransaction { amount: Amount(2), valid_after: Some(Timestamp::from_millis(now - 1000)), valid_until: Some(Timestamp::from_millis(now + 60_000)), ..trx.clone() };
+        // Too close to expiring for peers with clocks a little ahead to admit it.
+        let closing = AccountTransaction { amount: Amount(3), valid_until: Some(Timestamp::from_millis(now + 1000)), ..trx.clone() };
+        for trx in [trx, early, current, closing.clone()] {
+            node_a.send(keys[0].private.sign(trx)?).await;
+        }
+        sleep(SHORT).await;
+
+        assert_eq!(node_a.metrics.rejected(Rejection::Expired), 3);
+        for node in [&node_a, &node_b] {
+            assert_eq!(node.get_balance(&keys[1].public), Amount(1_000_000 + 2));
+            assert_eq!(node.get_account_history(&keys[1].public, &HistoryFilter::default()).len(), 1);
+        }
+
+        // A peer with a slower clock may have admitted it, so it is applied when relayed.
+        let config = bincode::config::standard();
+        let mut stream = TcpStream::connect(node_b.get_address()).await?;
+        let handshake = Handshake::new(Genesis::testnet()?.id(), Features::default());
+        write_frame(&mut stream, &bincode::encode_to_vec(&handshake, config)?).await?;
+        read_frame(&mut stream).await?;
+        write_frame(&mut stream, &bincode::encode_to_vec(Packet::Broadcast(keys[0].private.sign(closing)?), config)?).await?;
+        sleep(SHORT).await;
+
+        for node in [&node_a, &node_b] {
+            assert_eq!(node.get_balance(&keys[1].public), Amount(1_000_000 + 2 + 3));
+        }
+
+        // Once it can't be admitted anymore it is evicted, and the balance stays.
+        let history = &node_b.state.history;
+        assert_eq!(history.evict_expired(Timestamp::from_millis(now)), 0);
+        assert_eq!(history.evict_expired(Timestamp::from_millis(now + 2000)), 1);
+        assert_eq!(history.query(&keys[1].public, &HistoryFilter::default()).len(), 1);
+        assert_eq!(node_b.get_balance(&keys[1].public), Amount(1_000_000 + 2 + 3));
+
+        let never = AccountTransaction {
+            valid_after: Some(Timestamp::from_millis(now + 1)),
+            valid_until: Some(Timestamp::from_millis(now)),
+            ..node_a.get_account_history(&keys[1].public, &HistoryFilter::default()).remove(0)
+        };
+        assert!(node_a.get_ledger().update(&never).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn low_fee_transactions_are_rejected() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::with_config("NodeA", NodeConfig { min_relay_fee: Amount(5), ..Default::default() }).await?;
+        let node_b = Node::new("NodeB").await?;
+        let keys = genesis_accounts(2)?;
+        node_a.connect(node_b.get_address()).await?;
+
+        for fee in [1, 5] {
+            let trx = AccountTransaction {
+                fee: Amount(fee),
+                ..dkk_transfer(keys[0].public, keys[1].public, 10)
+            };
+            node_a.send(keys[0].private.sign(trx)?).await;
+        }
+        sleep(SHORT).await;
+
+        // Neither applied nor relayed, so both nodes agree.
+        for node in [&node_a, &node_b] {
+            assert_eq!(node.get_balance(&keys[1].public), Amount(10));
+            assert_eq!(node.get_balance(&keys[0].public), Amount(-15));
+        }
+        assert_eq!(node_a.metrics.rejected(Rejection::LowFee), 1);
+        assert_eq!(node_a.get_ledger().state_root(), node_b.get_ledger().state_root());
+
+        // A node with a lower minimum applies what the other rejects, so they diverge.
+        let trx = AccountTransaction {
+            fee: Amount(1),
+            ..dkk_transfer(keys[0].public, keys[1].public, 10)
+        };
+        node_b.send(keys[0].private.sign(trx)?).await;
+        sleep(SHORT).await;
+        assert_eq!(node_b.get_balance(&keys[1].public), Amount(20));
+        assert_eq!(node_a.get_balance(&keys[1].public), Amount(10));
+        assert_eq!(node_a.metrics.rejected(Rejection::LowFee), 2);
+        assert_ne!(node_a.get_ledger().state_root(), node_b.get_ledger().state_root());
+
+        // A minimum fee that overflows can't be paid.
+        assert_eq!(node_a.min_relay_fee(3), Some(Amount(15)));
+        assert_eq!(node_a.min_relay_fee(usize::MAX / 2), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_transactions_stay_out_of_history() -> anyhow::Result<()> {
+        log_init();
+
+        let node = Node::new("NodeA").await?;
+        let keys = genesis_accounts(2)?;
+        let root = node.state.history.root();
+
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 0);
+        node.send(keys[0].private.sign(trx.clone())?).await;
+        sleep(SHORT).await;
+
+        assert_eq!(node.metrics.rejected(Rejection::Ledger), 1);
+        assert!(!node.state.history.contains(&trx));
+        assert_eq!(node.state.history.len(), 0);
+        assert!(node.state.history.query(&keys[0].public, &HistoryFilter::default()).is_empty());
+        assert_eq!(node.state.history.root(), root);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_messages_are_flooded() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::new("NodeA").await?;
+        let node_b = Node::new("NodeB").await?;
+        let node_c = Node::new("NodeC").await?;
+        node_a.connect(node_b.get_address()).await?;
+        node_b.connect(node_c.get_address()).await?;
+        let mut chat = node_c.subscribe_chat();
+
+        node_a.chat("hello")?;
+        let msg = tokio::time::timeout(SHORT, chat.recv()).await??;
+        assert_eq!(msg.text, "hello");
+
+        // Seen messages aren't delivered twice.
+        node_b.relay_chat(msg);
+        sleep(SHORT).await;
+        assert!(chat.try_recv().is_err());
+
+        // Overlong messages are refused, both when sent and when decoded.
+        let long = "a".repeat(MAX_CHAT_LEN + 1);
+        assert!(node_a.chat(&long).is_err());
+        let packet = Packet::Chat(ChatMessage { id: [0; 16], text: long });
+        let bytes = bincode::encode_to_vec(&packet, bincode::config::standard())?;
+        assert!(bincode::decode_from_slice::<Packet, _>(&bytes, decode_config()).is_err());
+        let huge: Vec<u8> = std::iter::once(253).chain((1u64 << 40).to_le_bytes()).collect();
+        assert!(bincode::decode_from_slice::<String, _>(&huge, decode_config()).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dandelion_stems_before_fluffing() -> anyhow::Result<()> {
+        log_init();
+
+        let config = bincode::config::standard();
+        let dandelion = DandelionConfig { embargo: Duration::from_millis(500), ..Default::default() };
+        let node = Node::with_config("NodeA", NodeConfig { dandelion: Some(dandelion), ..Default::default() }).await?;
+
+        // A fake peer, so we can see exactly what the node sends it.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let handshake = Handshake::new(Genesis::dev().id(), Features::default());
+        let (_, (mut stream, _)) = tokio::try_join!(node.connect(listener.local_addr()?), async {
+            let (mut stream, addr) = listener.accept().await?;
+            write_frame(&mut stream, &bincode::encode_to_vec(&handshake, config)?).await?;
+            read_frame(&mut stream).await?;
+            anyhow::Ok((stream, addr))
+        })?;
+
+        let keys = genesis_accounts(2)?;
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 10);
+        node.send(keys[0].private.sign(trx)?).await;
+
+        // The transaction is passed on along the stem without being applied,
+        let (is_stem, _) = tokio::time::timeout(SHORT, next_trx(&mut stream)).await??;
+        assert!(is_stem);
+        assert_eq!(node.get_ledger().get(&keys[1].public), None);
+
+        // and fluffed once the embargo expires, since we never fluffed it.
+        let (is_stem, _) = tokio::time::timeout(_MID, next_trx(&mut stream)).await??;
+        assert!(!is_stem);
+        assert_eq!(node.get_balance(&keys[1].public), Amount(10));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() -> anyhow::Result<()> {
+        log_init();
+
+        let node_a = Node::new("NodeA").await?;
+        let node_b = Node::new("NodeB").await?;
+        node_a.connect(node_b.get_address()).await?;
+        let addr = node_a.serve_metrics("127.0.0.1:0".parse()?).await?;
+
+        let keys = genesis_accounts(2)?;
+        let trx = dkk_transfer(keys[0].public, keys[1].public, 10);
+        let signed = keys[0].private.sign(trx.clone())?;
+        node_a.send(signed.clone()).await;
+        node_a.send(signed).await;
+        node_a.send(keys[1].private.sign(AccountTransaction { amount: Amount(11), ..trx })?).await;
+        sleep(SHORT).await;
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for line in [
+            "dkk_transactions_received_total 3",
+            "dkk_transactions_applied_total 1",
+            "dkk_transactions_rejected_total{reason=\"duplicate\"} 1",
+            "dkk_transactions_rejected_total{reason=\"invalid_signature\"} 1",
+            "dkk_confirmation_latency_ms_count 1",
+            "dkk_peers 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing '{}' in:\n{}", line, response);
+        }
+        let sent = format!("dkk_peer_bytes_sent_total{{peer=\"{}\"}} ", node_b.get_address());
+        assert!(response.lines().any(|l| l.starts_with(&sent) && !l.ends_with(" 0")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bench_confirms_every_transaction() -> anyhow::Result<()> {
+        log_init();
+
+        let config = BenchConfig { nodes: 2, trxs: 20, timeout: _LONG, ..Default::default() };
+        let result = run_once(&config, 0).await?;
+
+        assert_eq!(result.confirmed, 20);
+        assert!(result.percentile(0.5) <= result.percentile(1.0));
+        assert!(result.tps() > 0.0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bench_reports_divergence() -> anyhow::Result<()> {
+        log_init();
+
+        // Honest nodes end up applying every version of an equivocated transfer.
+        let config = BenchConfig { nodes: 3, trxs: 30, timeout: _LONG, behaviour: Behaviour::Equivocating, ..Default::default() };
+        let result = run_once(&config, 1).await?;
+
+        assert_eq!(result.confirmed, 30);
+        assert_eq!(result.diverged, 0);
+        assert!(result.to_csv(config.trxs).starts_with("1,30,0,0,"));
+
+        Ok(())
+    }
+
+    /// Reads packets until a transaction, returning whether it was stemmed.
+    async fn next_trx(stream: &mut TcpStream) -> anyhow::Result<(bool, SignedAccountTransaction)> {
+        loop {
+            let (packet, _): (Packet, _) = bincode::decode_from_slice(&read_frame(stream).await?, bincode::config::standard())?;
+            match packet {
+                Packet::Stem(trx) => return Ok((true, trx)),
+                Packet::Broadcast(trx) => return Ok((false, trx)),
+                _ => (),
+            }
+        }
+    }
+
+    async fn write_frame(stream: &mut TcpStream, bytes: &[u8]) -> anyhow::Result<()> {
+        stream.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+        stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await?;
+        let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn handshake_and_unknown_packets() -> anyhow::Result<()> {
+        log_init();
+
+        let config = bincode::config::standard();
+        let node = Node::new("NodeA").await?;
+        let handshake = Handshake::new(Genesis::dev().id(), Features::default());
+
+        // A node speaking a newer protocol version, sending a packet we don't know.
+        let mut stream = TcpStream::connect(node.get_address()).await?;
+        let newer = Handshake { version: PROTOCOL_VERSION + 1, ..handshake.clone() };
+        write_frame(&mut stream, &bincode::encode_to_vec(&newer, config)?).await?;
+        let (theirs, _): (Handshake, _) = bincode::decode_from_slice(&read_frame(&mut stream).await?, config)?;
+        assert_eq!(theirs, handshake);
+
+        write_frame(&mut stream, &[250, 1, 2, 3]).await?;
+        // A chat message claiming to be 2^40 bytes long mustn't be allocated.
+        let mut huge = bincode::encode_to_vec(Packet::Chat(ChatMessage { id: [0; 16], text: String::new() }), config)?;
+        huge.pop();
+        huge.push(253);
+        huge.extend((1u64 << 40).to_le_bytes());
+        write_frame(&mut stream, &huge).await?;
+        write_frame(&mut stream, &bincode::encode_to_vec(Packet::GetPeers, config)?).await?;
+        let response = tokio::time::timeout(_MID, async {
+            loop {
+                let (packet, _): (Packet, _) = bincode::decode_from_slice(&read_frame(&mut stream).await?, config)?;
+                if let Packet::ResponseGetPeers(_) = packet {
+                    return anyhow::Ok(())
+                }
+            }
+        });
+        response.await??;
+
+        // Anything that isn't a DKK node is disconnected after the handshake.
+        let mut stream = TcpStream::connect(node.get_address()).await?;
+        let other = Handshake { magic: *b"BTC\0", ..handshake.clone() };
+        write_frame(&mut stream, &bincode::encode_to_vec(&other, config)?).await?;
+        read_frame(&mut stream).await?;
+        assert!(read_frame(&mut stream).await.is_err());
+
+        // As is a node speaking a protocol we can no longer decode.
+        let mut stream = TcpStream::connect(node.get_address()).await?;
+        let older = Handshake { version: MIN_PROTOCOL_VERSION - 1, ..handshake.clone() };
+        write_frame(&mut stream, &bincode::encode_to_vec(&older, config)?).await?;
+        read_frame(&mut stream).await?;
+        assert!(read_frame(&mut stream).await.is_err());
+
+        Ok(())
+    }
+
     //#[tokio::test(flavor = "multi_thread")]
     #[tokio::test]
     async fn it_works() -> anyhow::Result<()> {
@@ -34,20 +953,10 @@ mod tests {
         node_a.connect(node_b.get_address()).await?;
         node_b.connect(node_c.get_address()).await?;
 
-        let trx_1 = AccountTransaction {
-            from: a_keys.public,
-            to: b_keys.public,
-            amount: Amount(100),
-            timestamp: Timestamp::since_unix().unwrap(),
-        };
+        let trx_1 = dkk_transfer(a_keys.public, b_keys.public, 100);
         let strx_1 = a_keys.private.sign(trx_1).unwrap();
 
-        let trx_2 = AccountTransaction {
-            from: b_keys.public,
-            to: c_keys.public,
-            amount: Amount(150),
-            timestamp: Timestamp::since_unix().unwrap(),
-        };
+        let trx_2 = dkk_transfer(b_keys.public, c_keys.public, 150);
         let strx_2 = b_keys.private.sign(trx_2).unwrap();
 
         node_a.send(strx_1).await;
@@ -69,11 +978,11 @@ mod tests {
         println!("NodeB: {:?}", node_b.get_ledger());
         println!("NodeC: {:?}\n", node_c.get_ledger());
 
-        assert!(node_a.get_peers().len() != 0);
+        assert!(!node_a.get_peers().is_empty());
         assert_eq!(node_a.get_peers(), node_b.get_peers());
         
-        assert!(node_a.get_ledger().len() != 0);
-        assert_eq!(node_a.get_ledger(), node_b.get_ledger());
+        assert!(!node_a.get_ledger().is_empty());
+        assert_eq!(node_a.get_ledger().diff(&node_b.get_ledger()), vec![]);
 
         println!("Balances:");
         println!("Acc A: {:?}", node_a.get_balance(&a_keys.public));
@@ -96,5 +1005,417 @@ mod tests {
 
         Ok(())
     }
-    
+
+    #[test]
+    fn keystore_round_trip() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("p2p-test-{}.keystore", rand::random::<u64>()));
+
+        let mut keystore = Keystore::open(&path, "hunter2")?;
+        let alice = keystore.generate("alice")?;
+        let secret = keystore.export("alice")?;
+        keystore.select("alice")?;
+
+        let keystore = Keystore::open(&path, "hunter2")?;
+        assert!(keystore.selected().is_some_and(|(name, keys)| name == "alice" && keys == alice));
+        assert!(Keystore::open(&path, "hunter3").is_err());
+
+        let mut other = Keystore::open(path.with_extension("other"), "hunter2")?;
+        assert!(other.import("alice", &secret)? == alice);
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(path.with_extension("other"))?;
+
+        Ok(())
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Test vector 1 for ed25519 from SLIP-0010.
+    #[test]
+    fn slip10_test_vector() -> anyhow::Result<()> {
+        let seed: Vec<u8> = (0u8..16).collect();
+
+        let master = ExtendedKey::master(&seed);
+        assert_eq!(hex(&master.chain_code), "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb");
+        assert_eq!(hex(&master.secret), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+
+        let child = master.derive_path("m/0'")?;
+        assert_eq!(hex(&child.chain_code), "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69");
+        assert_eq!(hex(&child.secret), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+
+        assert!(master.derive_path("m/0").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn genesis_accounts_are_deterministic() -> anyhow::Result<()> {
+        let a = genesis_accounts(10)?;
+        let b = genesis_accounts(10)?;
+
+        assert!(a == b);
+        assert!(a[0] != a[1]);
+        assert!(derive_account(GENESIS_MNEMONIC, "", 3)? == a[3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn address_round_trip() -> anyhow::Result<()> {
+        let id = genesis_accounts(1)?[0].public;
+        let address = id.to_string();
+
+        assert!(address.starts_with("dkk1"));
+        assert_eq!(Id::from_str(&address)?, id);
+        assert_eq!(Id::from_str(&address.to_uppercase())?, id);
+
+        // Legacy base64, quoted as the old `Display` printed it.
+        let legacy = format!("\"{}\"", <base64ct::Base64 as base64ct::Encoding>::encode_string(id.as_bytes()));
+        assert_eq!(Id::from_str(&legacy)?, id);
+
+        let mut typo = address.clone().into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        assert!(matches!(Id::from_str(std::str::from_utf8(&typo)?), Err(AddressError::Bech32(_))));
+
+        let other = bech32::encode::<bech32::Bech32m>(bech32::Hrp::parse("eur")?, id.as_bytes())?;
+        assert!(matches!(Id::from_str(&other), Err(AddressError::WrongPrefix(..))));
+        assert!(matches!(Id::from_str("hello"), Err(AddressError::Malformed(_))));
+
+        // Only bech32m checksums are accepted, not those of plain bech32.
+        let plain = bech32::encode::<bech32::Bech32>(ADDRESS_HRP, id.as_bytes())?;
+        assert!(matches!(Id::from_str(&plain), Err(AddressError::Bech32(_))));
+
+        let asset = AssetId::new(&id, "COIN");
+        assert_eq!(AssetId::from_str(&asset.to_string())?, asset);
+        assert!(matches!(AssetId::from_str(&address), Err(AddressError::WrongPrefix(..))));
+        let plain = bech32::encode::<bech32::Bech32>(ASSET_HRP, asset.as_bytes())?;
+        assert!(matches!(AssetId::from_str(&plain), Err(AddressError::Bech32(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multisig_threshold() -> anyhow::Result<()> {
+        let keys = genesis_accounts(3)?;
+        let policy = MultisigPolicy::new(2, keys.iter().map(|k| k.public).collect())?;
+
+        let trx = dkk_transfer(policy.id(), keys[0].public, 10);
+        let mut a = SignedAccountTransaction::multisig(policy.clone(), trx.clone())?;
+        let mut b = SignedAccountTransaction::from_base64(&a.to_base64()?)?;
+
+        a.add_signature(&keys[0].private)?;
+        a.add_signature(&keys[0].private)?;
+        assert!(!a.verify());
+
+        b.add_signature(&keys[2].private)?;
+        a.merge(&b)?;
+        assert!(a.verify());
+
+        // A signer outside the policy can't sign, and a single key can't spend from the account.
+        let outsider = genesis_accounts(4)?.remove(3);
+        assert!(a.add_signature(&outsider.private).is_err());
+        assert!(!outsider.private.sign(trx)?.verify());
+        assert!(MultisigPolicy::new(4, policy.keys().to_vec()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_verification() -> anyhow::Result<()> {
+        let keys = genesis_accounts(4)?;
+        let policy = MultisigPolicy::new(2, keys[..3].iter().map(|k| k.public).collect())?;
+
+        let mut trxs = vec![];
+        for (i, from) in keys.iter().enumerate() {
+            let trx = dkk_transfer(from.public, keys[(i + 1) % keys.len()].public, i as i64 + 1);
+            trxs.push(from.private.sign(trx)?);
+        }
+        let trx = dkk_transfer(policy.id(), keys[3].public, 7);
+        let mut multisig = SignedAccountTransaction::multisig(policy, trx)?;
+        multisig.add_signature(&keys[0].private)?;
+        multisig.add_signature(&keys[1].private)?;
+        trxs.push(multisig);
+
+        assert_eq!(SignedAccountTransaction::verify_batch(&trxs), Ok(()));
+        assert_eq!(SignedAccountTransaction::verify_batch(&[]), Ok(()));
+
+        // Tamper with a signed payload, invalidating its signature.
+        trxs[2].trx.amount = Amount(1000);
+        assert_eq!(SignedAccountTransaction::verify_batch(&trxs), Err(2));
+
+        // A small order R is refused before batching, as `verify_strict` does.
+        let Authorization::Single(signature) = trxs[1].auth else { panic!("{:?} isn't single signed", trxs[1]) };
+        let mut bytes = ed25519_dalek::Signature::from(signature).to_bytes();
+        // The identity, encoded as y = 1.
+        bytes[..32].fill(0);
+        bytes[0] = 1;
+        trxs[1].auth = Authorization::Single(ed25519_dalek::Signature::from_bytes(&bytes).into());
+        assert!(!trxs[1].verify());
+        assert_eq!(SignedAccountTransaction::verify_batch(&trxs), Err(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batched_receive() -> anyhow::Result<()> {
+        log_init();
+
+        let node = Node::new("NodeA").await?;
+        let keys = genesis_accounts(2)?;
+        let mut trxs = vec![];
+        for i in 0..10 {
+            let trx = dkk_transfer(keys[0].public, keys[1].public, i + 1);
+            trxs.push(keys[0].private.sign(trx)?);
+        }
+        trxs[4].trx.amount = Amount(1000);
+
+        // The node agrees with verifying one by one, and caches what it verified.
+        assert_eq!(node.verify_batch(&trxs), Err(4));
+        assert!(node.state.verified.is_empty());
+        assert_eq!(node.verify_batch(&trxs[5..]), Ok(()));
+        assert_eq!(node.state.verified.len(), 5);
+        assert_eq!(node.verify_batch(&trxs), Err(4));
+
+        // Transactions arriving together are verified as a batch, the invalid one rejected.
+        let config = bincode::config::standard();
+        let mut stream = TcpStream::connect(node.get_address()).await?;
+        let handshake = Handshake::new(Genesis::dev().id(), Features::default());
+        write_frame(&mut stream, &bincode::encode_to_vec(&handshake, config)?).await?;
+        read_frame(&mut stream).await?;
+        for trx in &trxs {
+            write_frame(&mut stream, &bincode::encode_to_vec(Packet::Broadcast(trx.clone()), config)?).await?;
+        }
+        sleep(SHORT).await;
+
+        assert_eq!(node.metrics.rejected(Rejection::InvalidSignature), 1);
+        assert_eq!(node.metrics.trxs_applied.get(), 9);
+        assert_eq!(node.get_balance(&keys[1].public), Amount(55 - 5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merkle_proofs() {
+        for n in 0..10u8 {
+            let leaves: Vec<Hash> = (0..n).map(|i| Hash::leaf(&[i])).collect();
+            let tree = MerkleTree::new(leaves.clone());
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.prove(i).unwrap();
+                assert!(proof.verify(&tree.root(), leaf));
+                assert!(!proof.verify(&tree.root(), &Hash::leaf(&[n])));
+            }
+            assert!(tree.prove(n as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn state_root_is_order_independent() -> anyhow::Result<()> {
+        let keys = genesis_accounts(3)?;
+        let trxs: Vec<AccountTransaction> = (0..3)
+            .map(|i| dkk_transfer(keys[i].public, keys[(i + 1) % 3].public, 10 * (i as i64 + 1)))
+            .collect();
+
+        let a = Ledger::new();
+        let b = Ledger::new();
+        for trx in trxs.iter() {
+            a.update(trx)?;
+        }
+        for trx in trxs.iter().rev() {
+            b.update(trx)?;
+        }
+
+        assert_eq!(a.state_root(), b.state_root());
+        assert_ne!(a.state_root(), Ledger::new().state_root());
+
+        Ok(())
+    }
+
+    #[test]
+    fn balance_and_tx_proofs() -> anyhow::Result<()> {
+        let keys = genesis_accounts(6)?;
+        let ledger = Ledger::new();
+        let history = History::default();
+
+        let missing = keys[5].public;
+        assert_eq!(ledger.prove_balance(&missing).verify(&ledger.state_root(), &missing), Some(Amount(0)));
+
+        for i in 0..4 {
+            let trx = dkk_transfer(keys[i].public, keys[i + 1].public, i as i64 + 1);
+            ledger.update(&trx)?;
+            history.insert(trx);
+        }
+        let root = ledger.state_root();
+
+        for key in &keys[..5] {
+            let balance = ledger.get(&key.public);
+            assert_eq!(ledger.prove_balance(&key.public).verify(&root, &key.public), balance);
+        }
+        assert_eq!(ledger.prove_balance(&missing).verify(&root, &missing), Some(Amount(0)));
+
+        // A proof for one account can't be passed off as another's, nor verified against another root.
+        let proof = ledger.prove_balance(&keys[0].public);
+        assert_eq!(proof.verify(&root, &keys[1].public), None);
+        assert_eq!(proof.verify(&Ledger::new().state_root(), &keys[0].public), None);
+
+        // Hiding the middle of three balances behind its neighbours, with the right one
+        // claiming to be the second of two leaves, which its proof also verifies as.
+        let ledger = Ledger::new();
+        for key in &keys[..3] {
+            ledger.add(&key.public, Amount(50));
+        }
+        let root = ledger.state_root();
+        let entry = |i: usize| {
+            let (id, _) = *ledger.snapshot().iter().nth(i).unwrap().0;
+            match ledger.prove_balance(&id).inclusion {
+                Inclusion::Present(entry) => entry,
+                inclusion => panic!("{:?} is in the ledger, but got {:?}", id, inclusion),
+            }
+        };
+        let (left, middle, mut right) = (entry(0), entry(1), entry(2));
+        right.3 = MerkleProof { index: 1, leaf_count: 2, siblings: right.3.siblings.clone() };
+        assert!(right.3.verify(&root, &Hash::leaf(&[right.0.as_bytes().as_slice(), right.1.as_bytes(), &right.2.0.to_le_bytes()].concat())));
+        let forged = BalanceProof { state_root: root, inclusion: Inclusion::Absent { left: Some(left), right: Some(right) } };
+        assert_eq!(forged.verify(&root, &middle.0), None);
+
+        let trx = history.iter().next().unwrap().clone();
+        let proof = history.prove_tx(&trx.id()).unwrap();
+        assert!(proof.verify(&history.root(), &trx.id()));
+        assert!(!proof.verify(&Hash::default(), &trx.id()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_queries() -> anyhow::Result<()> {
+        let keys = genesis_accounts(3)?;
+        let (a, b, c) = (keys[0].public, keys[1].public, keys[2].public);
+        let history = History::default();
+        let ledger = Ledger::new();
+        ledger.add(&a, Amount(100));
+
+        // Inserted out of order, to check the index orders by timestamp.
+        for (from, to, amount, time) in [(a, b, 10, 3), (b, a, 4, 1), (a, c, 5, 2), (c, b, 1, 4)] {
+            let trx = AccountTransaction { timestamp: Timestamp::from_millis(time), ..dkk_transfer(from, to, amount) };
+            ledger.update(&trx)?;
+            history.insert(trx.clone());
+        }
+
+        let amounts = |trxs: Vec<AccountTransaction>| trxs.iter().map(|t| t.amount.0).collect::<Vec<_>>();
+        assert_eq!(amounts(history.query(&a, &HistoryFilter::default())), vec![4, 5, 10]);
+
+        let sent = HistoryFilter { direction: Direction::Sent, ..Default::default() };
+        assert_eq!(amounts(history.query(&a, &sent)), vec![5, 10]);
+
+        let page = HistoryFilter { offset: 1, limit: 1, ..Default::default() };
+        assert_eq!(amounts(history.query(&b, &page)), vec![10]);
+
+        let range = HistoryFilter {
+            after: Some(Timestamp::from_millis(2)),
+            before: Some(Timestamp::from_millis(3)),
+            ..Default::default()
+        };
+        assert_eq!(amounts(history.query(&c, &range)), vec![5]);
+
+        // In timestamp order rather than the order they were applied in.
+        let balances = |filter: &HistoryFilter| {
+            history.query_balances(&a, filter, |asset| ledger.get_asset(&a, asset))
+                .into_iter()
+                .map(|(t, b)| (t.amount.0, b.map(|b| b.0)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ledger.get(&a), Some(Amount(89)));
+        assert_eq!(balances(&HistoryFilter::default()), vec![(4, Some(104)), (5, Some(99)), (10, Some(89))]);
+        assert_eq!(balances(&sent), vec![(5, Some(99)), (10, Some(89))]);
+        assert_eq!(balances(&page), vec![(5, Some(99))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fees_conserve_supply() -> anyhow::Result<()> {
+        let keys = genesis_accounts(2)?;
+        let ledger = Ledger::new();
+        let history = History::default();
+
+        let mut trx = AccountTransaction {
+            fee: Amount(2),
+            ..dkk_transfer(keys[0].public, keys[1].public, 10)
+        };
+        ledger.update(&trx)?;
+        history.insert(trx.clone());
+
+        assert_eq!(ledger.get(&keys[0].public), Some(Amount(-12)));
+        assert_eq!(ledger.get(&keys[1].public), Some(Amount(10)));
+        assert_eq!(ledger.get(&FEE_POOL), Some(Amount(2)));
+        let balances = history.query_balances(&keys[0].public, &HistoryFilter::default(), |asset| ledger.get_asset(&keys[0].public, asset));
+        assert_eq!(balances.last().and_then(|x| x.1), Some(Amount(-12)));
+        ledger.check_supply(Amount(0))?;
+
+        trx.fee = Amount(-5);
+        assert!(ledger.update(&trx).is_err());
+        ledger.check_supply(Amount(0))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn ledger_snapshots() -> anyhow::Result<()> {
+        let keys = genesis_accounts(2)?;
+        let ledger = Ledger::new();
+        let trx = AccountTransaction {
+            fee: Amount(1),
+            ..dkk_transfer(keys[0].public, keys[1].public, 10)
+        };
+        ledger.update(&trx)?;
+        let snapshot = ledger.snapshot();
+
+        // A batch with an invalid transaction is rejected as a whole.
+        let invalid = AccountTransaction { amount: Amount(0), ..trx.clone() };
+        assert!(ledger.update_batch(&[trx.clone(), invalid]).is_err());
+        assert_eq!(ledger.snapshot(), snapshot);
+
+        ledger.update_batch(&[trx.clone(), trx])?;
+        assert_eq!(snapshot.get(&keys[1].public), Some(Amount(10)));
+        assert_eq!(ledger.get(&keys[1].public), Some(Amount(30)));
+        assert!(snapshot.iter().map(|(id, _)| id).is_sorted());
+
+        let json = serde_json::to_string(&snapshot)?;
+        let decoded: LedgerSnapshot = serde_json::from_str(&json)?;
+        assert_eq!(decoded, snapshot);
+        assert_eq!(Ledger::from(decoded).state_root(), snapshot.state_root());
+
+        Ok(())
+    }
+
+    #[test]
+    fn genesis_round_trip() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("p2p-test-{}.json", rand::random::<u64>()));
+
+        let mut genesis = Genesis::testnet()?;
+        genesis.save(&path)?;
+        assert_eq!(Genesis::load(&path)?, genesis);
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(genesis.total_supply(), Some(Amount(10_000_000)));
+        assert_ne!(genesis.id(), Genesis::dev().id());
+
+        let id = genesis.id();
+        genesis.bootstrap_peers.push("127.0.0.1:20000".parse()?);
+        assert_eq!(genesis.id(), id);
+
+        // Negative balances, or balances overflowing the supply, aren't loaded.
+        let keys = genesis_accounts(2)?;
+        for balances in [vec![Amount(-1)], vec![Amount(i64::MAX), Amount(1)]] {
+            let invalid = Genesis { balances: keys.iter().map(|k| k.public).zip(balances).collect(), ..Genesis::dev() };
+            invalid.save(&path)?;
+            assert!(Genesis::load(&path).is_err());
+            std::fs::remove_file(&path)?;
+        }
+
+        Ok(())
+    }
 }
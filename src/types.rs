@@ -1,279 +1,1498 @@
-use std::{fmt, net::SocketAddr, sync::Arc, time::{UNIX_EPOCH, SystemTime}};
-use bincode::{Encode, Decode};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt, net::SocketAddr, sync::{Arc, RwLock}, time::{Duration, UNIX_EPOCH, SystemTime}};
+use bincode::{de::{read::Reader, Decoder}, error::DecodeError, Encode, Decode};
 use ed25519_dalek::{VerifyingKey, SigningKey, Signer};
+use curve25519_dalek::edwards::CompressedEdwardsY;
 use rand::seq::SliceRandom;
 use tokio::{net::TcpStream, sync::mpsc::Sender};
 use dashmap::{DashMap, DashSet};
 use anyhow::anyhow;
-
+use serde::{Deserialize, Serialize};
 use base64ct::{Base64, Encoding};
+use sha2::{Digest, Sha256};
+
+use crate::{genesis::{AccountRules, Genesis}, log_fail, merkle::*, peer::{decode_config, Peer}};
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
+pub struct NodeName(pub String);
+
+impl fmt::Debug for NodeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Decode, Encode, Debug)]
+pub struct Signature([u8; 64]);
+
+impl From<ed25519_dalek::Signature> for Signature {
+    fn from(signature: ed25519_dalek::Signature) -> Signature {
+        Signature(signature.to_bytes())
+    }
+}
+
+impl From<Signature> for ed25519_dalek::Signature {
+    fn from(signature: Signature) -> ed25519_dalek::Signature {
+        ed25519_dalek::Signature::from_bytes(&signature.0)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone)]
+pub struct PrivateKey(SigningKey);
+
+impl From<SigningKey> for PrivateKey {
+    fn from(key: SigningKey) -> PrivateKey {
+        PrivateKey(key)
+    }
+}
+
+impl PrivateKey {
+    pub fn sign(&self, trx: AccountTransaction) -> anyhow::Result<SignedAccountTransaction> {
+        Ok(SignedAccountTransaction {
+            auth: Authorization::Single(self.sign_trx(&trx)?),
+            trx
+        })
+    }
+
+    /// Signs a registration we fund, along with `account`, the key of the
+    /// account being registered.
+    pub fn sign_creation(&self, creation: AccountCreation, account: &PrivateKey) -> anyhow::Result<SignedAccountCreation> {
+        Ok(SignedAccountCreation {
+            signature: self.sign_encoded(&creation)?,
+            consent: Authorization::Single(account.sign_encoded(&creation)?),
+            creation,
+        })
+    }
+
+    /// Signs a registration we fund of the multisig account of `policy`,
+    /// along with `signers`, enough of its keys to meet the threshold.
+    pub fn sign_multisig_creation(
+        &self,
+        creation: AccountCreation,
+        policy: MultisigPolicy,
+        signers: &[&PrivateKey],
+    ) -> anyhow::Result<SignedAccountCreation> {
+        if policy.id() != creation.id {
+            return Err(anyhow!("{} is not the multisig account {}", creation.id, policy.id()))
+        }
+
+        let mut signatures = vec![];
+        for signer in signers {
+            let pk = signer.get_pk();
+            let i = policy.keys
+                .iter()
+                .position(|k| *k == pk)
+                .ok_or_else(|| anyhow!("{} is not a signer of {}", pk, policy.id()))?;
+            signatures.push((i as u8, signer.sign_encoded(&creation)?));
+        }
+
+        Ok(SignedAccountCreation {
+            signature: self.sign_encoded(&creation)?,
+            consent: Authorization::Multi { policy, signatures },
+            creation,
+        })
+    }
+
+    pub fn sign_issuance(&self, issuance: AssetIssuance) -> anyhow::Result<SignedAssetIssuance> {
+        Ok(SignedAssetIssuance {
+            signature: self.sign_encoded(&issuance)?,
+            issuance,
+        })
+    }
+
+    fn sign_trx(&self, trx: &AccountTransaction) -> anyhow::Result<Signature> {
+        self.sign_encoded(trx)
+    }
+
+    fn sign_encoded(&self, value: &impl Encode) -> anyhow::Result<Signature> {
+        let bytes = bincode::encode_to_vec(value, bincode::config::standard())?;
+        Ok(self.0.sign(&bytes).into())
+    }
+
+    pub fn get_pk(&self) -> PublicKey {
+        self.0.verifying_key().into()
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+}
+
+pub type PublicKey = Id;
+
+#[derive(Eq, PartialEq, Clone)]
+pub struct KeyPair {
+    pub public: PublicKey,
+    pub private: PrivateKey,
+}
+
+impl KeyPair {
+    pub fn from_secret(secret: &[u8; 32]) -> KeyPair {
+        let sk = SigningKey::from_bytes(secret);
+        let pk = sk.verifying_key();
+        KeyPair {
+            private: sk.into(),
+            public: pk.into(),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, PartialOrd, Ord, Decode, Encode)]
+pub struct Id([u8; 32]);
+
+/// Whether `bytes` canonically encode a point of the prime order subgroup,
+/// other than the identity.
+fn is_prime_order(bytes: &[u8]) -> bool {
+    let Ok(bytes) = <[u8; 32]>::try_from(bytes) else { return false };
+    match CompressedEdwardsY(bytes).decompress() {
+        Some(point) => point.compress().0 == bytes && point.is_torsion_free() && !point.is_small_order(),
+        None => false,
+    }
+}
+
+impl Id {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn verify(&self, msg: &[u8], s: &Signature) -> bool {
+        let signature: ed25519_dalek::Signature = (*s).into();
+        // Multisig ids are hashes rather than keys, so this can legitimately fail.
+        let key = match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        key.verify_strict(msg, &signature).is_ok()
+    }
+}
+
+impl From<[u8; 32]> for Id {
+    fn from(bytes: [u8; 32]) -> Id {
+        Id(bytes)
+    }
+}
+
+impl From<VerifyingKey> for Id {
+    fn from(key: VerifyingKey) -> Id {
+        Id(VerifyingKey::to_bytes(&key))
+    }
+}
+
+#[derive(Copy, Eq, PartialEq, Hash, Clone, PartialOrd, Ord, Default, Encode, Decode, Serialize, Deserialize)]
+pub struct Amount(pub i64);
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::ops::Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Self::Output {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl std::ops::Sub for &Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: &Amount) -> Self::Output {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl std::ops::Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Self::Output {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl Amount {
+    /// `None` on overflow. Amounts from peers must be added with this.
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+}
+
+/// An asset is identified by its issuer and symbol, so only the issuer can
+/// issue it. See `address.rs` for how it is written.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, PartialOrd, Ord, Default, Decode, Encode)]
+pub struct AssetId([u8; 32]);
+
+impl AssetId {
+    /// The native currency. It is only ever created in the genesis.
+    pub const DKK: AssetId = AssetId([0; 32]);
+
+    pub fn new(issuer: &Id, symbol: &str) -> AssetId {
+        let mut hasher = Sha256::new();
+        hasher.update(issuer.as_bytes());
+        hasher.update(symbol.as_bytes());
+        AssetId(hasher.finalize().into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for AssetId {
+    fn from(bytes: [u8; 32]) -> AssetId {
+        AssetId(bytes)
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, PartialOrd, Ord, Encode, Decode)]
+pub struct Timestamp(u64);
+
+impl fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Duration::from_millis(self.0).as_secs())?;
+
+        Ok(())
+    }
+}
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp(millis)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    pub fn since_unix() -> anyhow::Result<Timestamp> {
+        Ok(Timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64))
+    }
+}
+
+/// An m-of-n multisig account. The account id is a hash committing to the
+/// threshold and the set of keys, see `MultisigPolicy::id`.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct MultisigPolicy {
+    threshold: u8,
+    keys: Vec<PublicKey>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, mut keys: Vec<PublicKey>) -> anyhow::Result<MultisigPolicy> {
+        // Sort the keys so the id doesn't depend on the order they were given in.
+        keys.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        keys.dedup();
+
+        let policy = MultisigPolicy { threshold, keys };
+        if !policy.is_valid() {
+            return Err(anyhow!("Invalid multisig policy: {} of {} keys", threshold, policy.keys.len()))
+        }
+
+        Ok(policy)
+    }
+
+    pub fn id(&self) -> Id {
+        let mut hasher = Sha256::new();
+        hasher.update(b"dkk-multisig");
+        hasher.update([self.threshold]);
+        for key in &self.keys {
+            hasher.update(key.as_bytes());
+        }
+        Id(hasher.finalize().into())
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    // Policies arrive over the wire, so `new` can't be relied upon to have checked them.
+    fn is_valid(&self) -> bool {
+        self.threshold >= 1 &&
+        self.keys.len() <= u8::MAX as usize &&
+        self.threshold as usize <= self.keys.len() &&
+        self.keys.windows(2).all(|w| w[0].as_bytes() < w[1].as_bytes())
+    }
+}
+
+/// The proof that the owner(s) of `trx.from` authorized a transaction.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub enum Authorization {
+    Single(Signature),
+    /// Signatures indexed by the position of the signer in `policy.keys`.
+    Multi { policy: MultisigPolicy, signatures: Vec<(u8, Signature)> },
+}
+
+impl Authorization {
+    /// Whether the owner(s) of `id` signed `bytes`.
+    pub fn verify(&self, id: &Id, bytes: &[u8]) -> bool {
+        match self {
+            Authorization::Single(signature) => id.verify(bytes, signature),
+            Authorization::Multi { policy, signatures } => {
+                if !policy.is_valid() || policy.id() != *id {
+                    return false
+                }
+
+                let mut signers: Vec<u8> = signatures
+                    .iter()
+                    .filter(|(i, signature)| policy.keys
+                        .get(*i as usize)
+                        .is_some_and(|key| key.verify(bytes, signature))
+                    )
+                    .map(|(i, _)| *i)
+                    .collect();
+                signers.sort();
+                signers.dedup();
+
+                signers.len() >= policy.threshold as usize
+            }
+        }
+    }
+}
+
+/// The hash of an `AccountTransaction` or `AssetIssuance`, identifying it
+/// independently of how it was signed.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, PartialOrd, Ord, Decode, Encode)]
+pub struct TxId([u8; 32]);
+
+impl fmt::Debug for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.0[..8] {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
+pub struct SignedAccountTransaction {
+    pub auth: Authorization,
+    pub trx: AccountTransaction
+}
+
+impl fmt::Debug for SignedAccountTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "󰱒 {:?}", self.trx)
+    }
+}
+
+impl SignedAccountTransaction {
+    /// Creates a multisig transaction without any signatures, to be passed
+    /// around and signed with `add_signature`.
+    pub fn multisig(policy: MultisigPolicy, trx: AccountTransaction) -> anyhow::Result<SignedAccountTransaction> {
+        if policy.id() != trx.from {
+            return Err(anyhow!("Transaction is not from the multisig account {}", policy.id()))
+        }
+
+        Ok(SignedAccountTransaction {
+            auth: Authorization::Multi { policy, signatures: vec![] },
+            trx
+        })
+    }
+
+    pub fn add_signature(&mut self, key: &PrivateKey) -> anyhow::Result<()> {
+        let signature = key.sign_trx(&self.trx)?;
+        match &mut self.auth {
+            Authorization::Single(_) => Err(anyhow!("Transaction is not a multisig transaction")),
+            Authorization::Multi { policy, signatures } => {
+                let pk = key.get_pk();
+                let i = policy.keys
+                    .iter()
+                    .position(|k| *k == pk)
+                    .ok_or_else(|| anyhow!("{} is not a signer of {}", pk, policy.id()))?;
+
+                if !signatures.iter().any(|(j, _)| *j as usize == i) {
+                    signatures.push((i as u8, signature));
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Merges the signatures of another partially signed copy of this transaction.
+    pub fn merge(&mut self, other: &SignedAccountTransaction) -> anyhow::Result<()> {
+        if self.trx != other.trx {
+            return Err(anyhow!("Can't merge signatures of different transactions"))
+        }
+
+        match (&mut self.auth, &other.auth) {
+            (
+                Authorization::Multi { policy, signatures },
+                Authorization::Multi { policy: other_policy, signatures: other_signatures }
+            ) if policy == other_policy => {
+                for (i, signature) in other_signatures {
+                    if !signatures.iter().any(|(j, _)| j == i) {
+                        signatures.push((*i, *signature));
+                    }
+                }
+
+                Ok(())
+            }
+            _ => Err(anyhow!("Can only merge multisig transactions with the same policy")),
+        }
+    }
+
+    /// Encodes the transaction for exchanging partially signed transactions offline.
+    pub fn to_base64(&self) -> anyhow::Result<String> {
+        let bytes = bincode::encode_to_vec(self, bincode::config::standard())?;
+        Ok(Base64::encode_string(&bytes))
+    }
+
+    pub fn from_base64(s: &str) -> anyhow::Result<SignedAccountTransaction> {
+        let bytes = Base64::decode_vec(s.trim()).map_err(|e| anyhow!("Invalid transaction: {}", e))?;
+        let (trx, _) = bincode::decode_from_slice(&bytes, decode_config())?;
+
+        Ok(trx)
+    }
+
+    /// Verifies many transactions at once, which is considerably faster than
+    /// verifying them one by one. On failure the index of the first invalid
+    /// transaction is returned.
+    pub fn verify_batch(trxs: &[SignedAccountTransaction]) -> Result<(), usize> {
+        let first_invalid = || match trxs.iter().position(|t| !t.verify()) {
+            Some(i) => Err(i),
+            None => Ok(()),
+        };
+
+        let mut messages = Vec::with_capacity(trxs.len());
+        for trx in trxs {
+            match bincode::encode_to_vec(&trx.trx, bincode::config::standard()) {
+                Ok(bytes) => messages.push(bytes),
+                Err(_) => return first_invalid(),
+            }
+        }
+
+        let mut msgs: Vec<&[u8]> = vec![];
+        let mut signatures: Vec<ed25519_dalek::Signature> = vec![];
+        let mut keys: Vec<VerifyingKey> = vec![];
+        for (trx, msg) in trxs.iter().zip(messages.iter()) {
+            let signers: Vec<(&Id, &Signature)> = match &trx.auth {
+                Authorization::Single(signature) => vec![(&trx.trx.from, signature)],
+                Authorization::Multi { policy, signatures } => {
+                    let mut indices: Vec<u8> = signatures.iter().map(|(i, _)| *i).collect();
+                    indices.sort();
+                    indices.dedup();
+                    let enough_signers = indices.len() == signatures.len() && indices.len() >= policy.threshold as usize;
+
+                    if !policy.is_valid() || policy.id() != trx.trx.from || !enough_signers {
+                        return first_invalid()
+                    }
+                    match signatures.iter().map(|(i, s)| policy.keys.get(*i as usize).map(|k| (k, s))).collect() {
+                        Some(signers) => signers,
+                        None => return first_invalid(),
+                    }
+                }
+            };
+
+            for (id, signature) in signers {
+                // Batch verification is not strict. It agrees with `verify` on canonical points
+                // without a small order component, anything else is left to `verify` to decide.
+                if !is_prime_order(id.as_bytes()) || !is_prime_order(&signature.0[..32]) {
+                    return first_invalid()
+                }
+                match VerifyingKey::from_bytes(id.as_bytes()) {
+                    Ok(key) => keys.push(key),
+                    Err(_) => return first_invalid(),
+                }
+                msgs.push(msg);
+                signatures.push((*signature).into());
+            }
+        }
+
+        match ed25519_dalek::verify_batch(&msgs, &signatures, &keys) {
+            Ok(_) => Ok(()),
+            Err(_) => first_invalid(),
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let bytes_res = bincode::encode_to_vec(&self.trx, bincode::config::standard());
+        let bytes = match bytes_res {
+            Ok(bs) => bs,
+            Err(_) => return false,
+        };
+
+        self.auth.verify(&self.trx.from, &bytes)
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
+pub struct AccountTransaction {
+    pub to: Id,
+    pub from: Id,
+    /// The asset of `amount`. Fees are always paid in DKK.
+    pub asset: AssetId,
+    pub amount: Amount,
+    /// Paid by the sender on top of `amount`, into the fee pool.
+    pub fee: Amount,
+    /// If set, `amount` is held in escrow until released, see `Lock`.
+    pub lock: Option<Lock>,
+    /// Further recipients, paid in the same asset along with `to`, all or
+    /// nothing. Locked transfers can't have any.
+    pub outputs: Vec<Output>,
+    /// Nodes only admit the transaction within this window, so it can't be
+    /// held back and released much later. `None` is unbounded.
+    pub valid_after: Option<Timestamp>,
+    pub valid_until: Option<Timestamp>,
+    /// Keeps otherwise identical transactions apart.
+    pub timestamp: Timestamp
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct Output {
+    pub to: Id,
+    pub amount: Amount,
+}
+
+/// A condition on a transfer, which holds the amount in an escrow account
+/// until an `EscrowRelease` satisfying it. There are no blocks yet, so locks
+/// are on the timestamp of the release rather than on a block height.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub enum Lock {
+    /// The receiver gets the amount once released at or after this time.
+    Time(Timestamp),
+    /// The receiver gets the amount if released with the preimage of the
+    /// SHA-256 `hash` before the deadline. After it, the sender can have it
+    /// refunded.
+    Hash { hash: [u8; 32], deadline: Timestamp },
+}
+
+/// Releases the escrow account `escrow`. Where the money goes is decided by
+/// the lock, so releases don't have to be signed. Nodes refuse releases
+/// timestamped far from when they receive them: ahead would release time
+/// locks early, and behind would claim hash locks after their deadline.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct EscrowRelease {
+    pub escrow: Id,
+    pub preimage: Option<Vec<u8>>,
+    pub timestamp: Timestamp,
+}
+
+/// Money held by a locked transfer.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Escrow {
+    pub from: Id,
+    pub to: Id,
+    pub asset: AssetId,
+    pub amount: Amount,
+    pub lock: Lock,
+}
+
+/// Registers the account `id`, funded by `from` with `deposit`. The fee
+/// goes to the fee pool, like a transaction fee. Both `from` and `id` sign
+/// it, so no one is registered, or given a label, against their will.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct AccountCreation {
+    pub id: Id,
+    pub from: Id,
+    pub deposit: Amount,
+    pub fee: Amount,
+    pub label: Option<String>,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct SignedAccountCreation {
+    pub signature: Signature,
+    /// By the owner(s) of `creation.id`, agreeing to be registered.
+    pub consent: Authorization,
+    pub creation: AccountCreation,
+}
+
+impl SignedAccountCreation {
+    pub fn verify(&self) -> bool {
+        match bincode::encode_to_vec(&self.creation, bincode::config::standard()) {
+            Ok(bytes) => self.creation.from.verify(&bytes, &self.signature) && self.consent.verify(&self.creation.id, &bytes),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Mints `amount` of the asset `symbol` to its issuer, who signs it. The
+/// first issuance of an asset fixes its supply cap, and later issuances must
+/// state the same cap.
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct AssetIssuance {
+    pub issuer: Id,
+    pub symbol: String,
+    pub cap: Amount,
+    pub amount: Amount,
+    pub timestamp: Timestamp,
+}
+
+impl AssetIssuance {
+    pub fn asset(&self) -> AssetId {
+        AssetId::new(&self.issuer, &self.symbol)
+    }
+
+    pub fn id(&self) -> TxId {
+        let bytes = bincode::encode_to_vec(self, bincode::config::standard())
+            .expect("Issuances can always be encoded");
+        TxId(Sha256::digest(bytes).into())
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
+pub struct SignedAssetIssuance {
+    pub signature: Signature,
+    pub issuance: AssetIssuance,
+}
+
+impl SignedAssetIssuance {
+    pub fn verify(&self) -> bool {
+        match bincode::encode_to_vec(&self.issuance, bincode::config::standard()) {
+            Ok(bytes) => self.issuance.issuer.verify(&bytes, &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+/// An issued asset, as defined by its first issuance.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Asset {
+    pub issuer: Id,
+    pub symbol: String,
+    pub cap: Amount,
+    pub issued: Amount,
+}
+
+/// What the ledger knows about a registered account, besides its balance.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct AccountInfo {
+    /// The timestamp of the transaction registering the account.
+    pub created: Timestamp,
+    pub label: Option<String>,
+}
+
+impl AccountTransaction {
+    pub fn id(&self) -> TxId {
+        let bytes = bincode::encode_to_vec(self, bincode::config::standard())
+            .expect("Transactions can always be encoded");
+        TxId(Sha256::digest(bytes).into())
+    }
+
+    pub fn is_valid_at(&self, now: Timestamp) -> bool {
+        self.valid_after.is_none_or(|after| now >= after) && self.valid_until.is_none_or(|until| now <= until)
+    }
+
+    /// Every recipient and the amount it gets, starting with `to`.
+    pub fn recipients(&self) -> impl Iterator<Item = (Id, Amount)> + '_ {
+        std::iter::once((self.to, self.amount)).chain(self.outputs.iter().map(|o| (o.to, o.amount)))
+    }
+
+    /// The sum of the amounts of every recipient, `None` if it overflows.
+    pub fn total_amount(&self) -> Option<Amount> {
+        self.recipients().try_fold(Amount(0), |total, (_, amount)| total.checked_add(amount))
+    }
+
+    /// The account holding the amount of a locked transfer. Like a multisig
+    /// id it is a hash rather than a key, so nobody can spend from it.
+    pub fn escrow_id(&self) -> Id {
+        let mut hasher = Sha256::new();
+        hasher.update(b"dkk-escrow");
+        hasher.update(self.id().0);
+        Id(hasher.finalize().into())
+    }
+}
+
+impl fmt::Display for AccountTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} -> {:?}: {:?}", self.from, self.to, self.amount)?;
+        for output in &self.outputs {
+            write!(f, ", {:?}: {:?}", output.to, output.amount)?;
+        }
+        write!(f, " {} (fee {:?} DKK)", self.asset, self.fee)?;
+        if let Some(lock) = &self.lock {
+            write!(f, " {:?}", lock)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AccountTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub type NodeRequest = (Packet, Peer);
+
+/// Sent first on every connection, to tell networks and nodes apart.
+pub const MAGIC: [u8; 4] = *b"DKK\0";
+
+/// The current protocol version, bumped whenever `Packet` changes.
+pub const PROTOCOL_VERSION: u16 = 11;
+
+/// The oldest protocol version still spoken. Peers older than this are disconnected.
+/// Raised to `PROTOCOL_VERSION` whenever the encoding of an existing packet
+/// changes, as older peers would misread it; added packets don't need it.
+pub const MIN_PROTOCOL_VERSION: u16 = 11;
+
+/// Optional capabilities of a node, as advertised in its handshake.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Decode, Encode, Default)]
+pub struct Features(pub u64);
+
+impl Features {
+    /// The node is a light node, and can't answer `GetProof` requests.
+    pub const LIGHT: Features = Features(1 << 0);
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#b}", self.0)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Decode, Encode, Debug)]
+pub struct Handshake {
+    pub magic: [u8; 4],
+    /// The genesis id of the chain the node is on.
+    pub network_id: Hash,
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Handshake {
+    pub fn new(network_id: Hash, features: Features) -> Handshake {
+        Handshake {
+            magic: MAGIC,
+            network_id,
+            version: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// Checks that a received handshake is compatible with our own.
+    pub fn check(&self, ours: &Handshake) -> anyhow::Result<()> {
+        if self.magic != ours.magic {
+            return Err(anyhow!("Not a DKK node, magic bytes were {:?}", self.magic))
+        }
+        if self.network_id != ours.network_id {
+            return Err(anyhow!("Node is on another network, {:?} != {:?}", self.network_id, ours.network_id))
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!("Node speaks protocol version {}, the minimum is {}", self.version, MIN_PROTOCOL_VERSION))
+        }
+
+        Ok(())
+    }
+}
+
+/// Only ever append new variants, older nodes skip variants they don't know.
+#[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
+pub enum Packet {
+    GetPeers,
+    AddPeer(SocketAddr),
+    Broadcast(SignedAccountTransaction),
+    ResponseGetPeers(Vec<SocketAddr>),
+    /// Periodically announced so peers can detect diverging ledgers.
+    StateRoot { history: Hash, state: Hash },
+    GetProof { nonce: u64, query: ProofQuery },
+    ResponseProof { nonce: u64, proof: Proof },
+    Chat(ChatMessage),
+    /// A transaction in the stem phase of Dandelion++, passed to a single peer.
+    Stem(SignedAccountTransaction),
+    /// Asks for the whole ledger and history, to find out where we diverged.
+    GetState { nonce: u64 },
+    ResponseState { nonce: u64, ledger: LedgerSnapshot, history: Vec<AccountTransaction> },
+    Register(SignedAccountCreation),
+    Issue(SignedAssetIssuance),
+    Release(EscrowRelease),
+}
+
+/// The longest chat message, in bytes, peers accept.
+pub const MAX_CHAT_LEN: usize = 1024;
+
+/// An anonymous chat message. The random id is only used to stop flooding
+/// the message once everyone has seen it, and says nothing about the sender.
+#[derive(Eq, PartialEq, Clone, Hash, Encode)]
+pub struct ChatMessage {
+    pub id: [u8; 16],
+    pub text: String,
+}
+
+impl ChatMessage {
+    pub fn new(text: String) -> anyhow::Result<ChatMessage> {
+        if text.len() > MAX_CHAT_LEN {
+            return Err(anyhow!("Chat messages can be at most {} bytes", MAX_CHAT_LEN))
+        }
+
+        Ok(ChatMessage {
+            id: rand::random(),
+            text,
+        })
+    }
+}
+
+impl Decode for ChatMessage {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let id = Decode::decode(decoder)?;
+        // Checked before reading the text, so an overlong one is never allocated.
+        let len = u64::decode(decoder)?;
+        if len > MAX_CHAT_LEN as u64 {
+            return Err(DecodeError::Other("chat message too long"))
+        }
+        decoder.claim_bytes_read(len as usize)?;
+        let mut bytes = vec![0; len as usize];
+        decoder.reader().read(&mut bytes)?;
+        let text = String::from_utf8(bytes).map_err(|e| DecodeError::Utf8 { inner: e.utf8_error() })?;
+
+        Ok(ChatMessage { id, text })
+    }
+}
+bincode::impl_borrow_decode!(ChatMessage);
+
+impl fmt::Debug for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "󰍩 {:?}", self.text)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
+pub enum ProofQuery {
+    Balance(Id),
+    Transaction(TxId),
+}
+
+#[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
+pub enum Proof {
+    Balance(BalanceProof),
+    /// `None` if the transaction is not in the history.
+    Transaction(Option<TxProof>),
+}
+
+#[derive(Eq, PartialEq, Clone, Hash, Encode, Decode)]
+pub enum RpcCall {
+    GetPeers,
+}
+
+/// The account collecting transaction fees. Its id is not a public key,
+/// so nobody can spend from it.
+pub const FEE_POOL: Id = Id([0; 32]);
+
+#[derive(Clone, Default)]
+struct Accounts {
+    balances: HashMap<(Id, AssetId), Amount>,
+    registered: HashMap<Id, AccountInfo>,
+    assets: HashMap<AssetId, Asset>,
+    /// Issuances already applied, so a replayed one doesn't mint again.
+    issuances: HashSet<TxId>,
+    /// Unreleased escrows, by the id of their escrow account.
+    escrows: HashMap<Id, Escrow>,
+    /// Escrows already released, so a release of one isn't mistaken for
+    /// one arriving before its lock.
+    released: HashSet<Id>,
+}
+
+impl Accounts {
+    fn is_registered(&self, id: &Id) -> bool {
+        *id == FEE_POOL || self.registered.contains_key(id) || self.escrows.contains_key(id)
+    }
+
+    fn balance(&self, id: &Id, asset: &AssetId) -> Amount {
+        self.balances.get(&(*id, *asset)).copied().unwrap_or_default()
+    }
+
+    fn add(&mut self, id: &Id, asset: &AssetId, amount: Amount) {
+        let balance = self.balances.entry((*id, *asset)).or_insert(Amount(0));
+        *balance = *balance + amount;
+    }
+
+    /// Adds every amount, or none of them if a balance would overflow.
+    fn add_all(&mut self, amounts: &[(Id, AssetId, Amount)]) -> anyhow::Result<()> {
+        let mut balances: HashMap<(Id, AssetId), Amount> = HashMap::new();
+        for (id, asset, amount) in amounts {
+            let balance = balances.get(&(*id, *asset)).copied().unwrap_or_else(|| self.balance(id, asset));
+            let balance = balance.checked_add(*amount).ok_or_else(|| anyhow!("The {} balance of {} would overflow", asset, id))?;
+            balances.insert((*id, *asset), balance);
+        }
+        self.balances.extend(balances);
+
+        Ok(())
+    }
+}
+
+/// The balance of every account in every asset, which accounts are
+/// registered, which assets are issued, and the unreleased escrows. A
+/// transaction is applied under a single write lock, so readers never see it
+/// half applied.
+///
+/// Like overdrafts, whether a transfer finds its recipient registered, or its
+/// asset issued, depends on the order transactions are applied in. So do
+/// which of two issuances exceeding the supply cap is rejected, and whether a
+/// hash locked escrow is claimed or refunded when both race the deadline. With strict
+/// account rules or assets, nodes can disagree until they apply transactions
+/// in the same order.
+#[derive(Clone)]
+pub struct Ledger {
+    accounts: Arc<RwLock<Accounts>>,
+    rules: AccountRules,
+}
+
+impl Default for Ledger {
+    fn default() -> Ledger {
+        Ledger::new()
+    }
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::with_rules(AccountRules::default())
+    }
+
+    pub fn with_rules(rules: AccountRules) -> Ledger {
+        Ledger {
+            accounts: Arc::new(RwLock::new(Accounts::default())),
+            rules,
+        }
+    }
+
+    /// The genesis accounts are registered at time zero.
+    pub fn from_genesis(genesis: &Genesis) -> Ledger {
+        let registered = genesis.balances
+            .keys()
+            .map(|id| (*id, AccountInfo { created: Timestamp::from_millis(0), label: None }))
+            .collect();
+        let accounts = Accounts {
+            balances: genesis.balances.iter().map(|(id, amount)| ((*id, AssetId::DKK), *amount)).collect(),
+            registered,
+            ..Default::default()
+        };
+
+        Ledger {
+            accounts: Arc::new(RwLock::new(accounts)),
+            rules: genesis.consensus.accounts.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.read().unwrap().balances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.read().unwrap().balances.is_empty()
+    }
+
+    /// A consistent copy of the balances, unaffected by later updates.
+    pub fn snapshot(&self) -> LedgerSnapshot {
+        LedgerSnapshot(self.accounts.read().unwrap().balances.iter().map(|(key, amount)| (*key, *amount)).collect())
+    }
+
+    pub fn update(&self, trx: &AccountTransaction) -> anyhow::Result<()> {
+        self.apply(&mut self.accounts.write().unwrap(), trx, false)
+    }
+
+    /// Applies every transaction or, if any of them is invalid, none of them.
+    pub fn update_batch(&self, trxs: &[AccountTransaction]) -> anyhow::Result<()> {
+        let mut accounts = self.accounts.write().unwrap();
+        let mut updated = accounts.clone();
+        for trx in trxs {
+            self.apply(&mut updated, trx, false)?;
+        }
+        *accounts = updated;
+
+        Ok(())
+    }
+
+    /// Like `update`, but refuses to overdraw the sender. Whether a
+    /// transaction overdraws depends on the order transactions are applied
+    /// in, so this is only safe once every node applies them in the same
+    /// order. Until then nodes use `update`, and balances may go negative.
+    pub fn update_funded(&self, trx: &AccountTransaction) -> anyhow::Result<()> {
+        self.apply(&mut self.accounts.write().unwrap(), trx, true)
+    }
+
+    /// Registers `creation.id`, moving the deposit and fee from `creation.from`.
+    pub fn register(&self, creation: &AccountCreation) -> anyhow::Result<()> {
+        if creation.deposit.0 < 0 || creation.deposit < self.rules.min_deposit {
+            return Err(anyhow!("Deposit must be at least {:?} DKK: {:?}", self.rules.min_deposit, creation))
+        }
+        if creation.fee.0 < 0 || creation.fee < self.rules.registration_fee {
+            return Err(anyhow!("Registration fee must be at least {:?} DKK: {:?}", self.rules.registration_fee, creation))
+        }
+
+        let total = creation.deposit.checked_add(creation.fee).ok_or_else(|| anyhow!("Registration amounts overflow: {:?}", creation))?;
+
+        let mut accounts = self.accounts.write().unwrap();
+        if accounts.is_registered(&creation.id) {
+            return Err(anyhow!("{} is already registered", creation.id))
+        }
+        if !accounts.is_registered(&creation.from) && !self.rules.implicit_registration {
+            return Err(anyhow!("{} is not registered", creation.from))
+        }
+
+        let mut amounts = vec![(creation.from, AssetId::DKK, Amount(0) - total), (creation.id, AssetId::DKK, creation.deposit)];
+        if creation.fee != Amount(0) {
+            amounts.push((FEE_POOL, AssetId::DKK, creation.fee));
+        }
+        accounts.add_all(&amounts)?;
+
+        accounts.registered.entry(creation.from).or_insert(AccountInfo { created: creation.timestamp, label: None });
+        accounts.registered.insert(creation.id, AccountInfo { created: creation.timestamp, label: creation.label.clone() });
+
+        Ok(())
+    }
+
+    /// Mints an issuance to its issuer, defining the asset if it is the first.
+    pub fn issue(&self, issuance: &AssetIssuance) -> anyhow::Result<()> {
+        if issuance.amount.0 <= 0 {
+            return Err(anyhow!("Issued amount must be positive: {:?}", issuance))
+        }
+        if issuance.symbol.is_empty() {
+            return Err(anyhow!("Asset symbol must not be empty: {:?}", issuance))
+        }
+
+        let asset = issuance.asset();
+        let mut accounts = self.accounts.write().unwrap();
+        if accounts.issuances.contains(&issuance.id()) {
+            return Err(anyhow!("Already issued: {:?}", issuance))
+        }
+        let issued = match accounts.assets.get(&asset) {
+            Some(existing) if existing.cap != issuance.cap => {
+                return Err(anyhow!("The supply cap of {} is {:?}, not {:?}", asset, existing.cap, issuance.cap))
+            }
+            Some(existing) => existing.issued,
+            None => Amount(0),
+        };
+        let issued = match issued.checked_add(issuance.amount) {
+            Some(issued) if issued <= issuance.cap => issued,
+            _ => return Err(anyhow!("Issuing {:?} {} would exceed the supply cap of {:?}", issuance.amount, asset, issuance.cap)),
+        };
+        if !accounts.is_registered(&issuance.issuer) && !self.rules.implicit_registration {
+            return Err(anyhow!("{} is not registered", issuance.issuer))
+        }
+        // Overdrafts elsewhere let the issuer hold more than was ever issued.
+        accounts.add_all(&[(issuance.issuer, asset, issuance.amount)])?;
+
+        accounts.registered.entry(issuance.issuer).or_insert(AccountInfo { created: issuance.timestamp, label: None });
+        accounts.assets
+            .entry(asset)
+            .or_insert_with(|| Asset {
+                issuer: issuance.issuer,
+                symbol: issuance.symbol.clone(),
+                cap: issuance.cap,
+                issued: Amount(0),
+            })
+            .issued = issued;
+        accounts.issuances.insert(issuance.id());
+
+        Ok(())
+    }
+
+    /// Pays out an escrow as its lock decides, returning who got the money.
+    pub fn release(&self, release: &EscrowRelease) -> anyhow::Result<Id> {
+        let mut accounts = self.accounts.write().unwrap();
+        let escrow = accounts.escrows
+            .get(&release.escrow)
+            .ok_or_else(|| anyhow!("{} is not an unreleased escrow", release.escrow))?;
+
+        let to = match &escrow.lock {
+            Lock::Time(unlock) if release.timestamp >= *unlock => escrow.to,
+            Lock::Time(unlock) => return Err(anyhow!("{} is locked until {:?}", release.escrow, unlock)),
+            Lock::Hash { hash, deadline } => match &release.preimage {
+                Some(preimage) if release.timestamp < *deadline && Sha256::digest(preimage)[..] == hash[..] => escrow.to,
+                Some(_) => return Err(anyhow!("Wrong or late preimage for {}", release.escrow)),
+                None if release.timestamp >= *deadline => escrow.from,
+                None => return Err(anyhow!("{} can't be refunded before {:?}", release.escrow, deadline)),
+            },
+        };
+
+        let (asset, amount) = (escrow.asset, escrow.amount);
+        accounts.add_all(&[(release.escrow, asset, Amount(0) - amount), (to, asset, amount)])?;
+        accounts.escrows.remove(&release.escrow);
+        accounts.released.insert(release.escrow);
+
+        Ok(to)
+    }
+
+    pub fn escrow(&self, id: &Id) -> Option<Escrow> {
+        self.accounts.read().unwrap().escrows.get(id).cloned()
+    }
+
+    pub fn is_released(&self, id: &Id) -> bool {
+        self.accounts.read().unwrap().released.contains(id)
+    }
+
+    /// `None` if the asset has never been issued. DKK isn't issued.
+    pub fn asset(&self, asset: &AssetId) -> Option<Asset> {
+        self.accounts.read().unwrap().assets.get(asset).cloned()
+    }
+
+    /// `None` if the account is not registered.
+    pub fn account(&self, id: &Id) -> Option<AccountInfo> {
+        self.accounts.read().unwrap().registered.get(id).cloned()
+    }
+
+    fn check(trx: &AccountTransaction) -> anyhow::Result<()> {
+        if trx.recipients().any(|(_, amount)| amount.0 <= 0) {
+            return Err(anyhow!("Transaction amounts must be positive: {:?}", trx))
+        }
+        if trx.fee.0 < 0 {
+            return Err(anyhow!("Transaction has a negative fee: {:?}", trx))
+        }
+        if trx.lock.is_some() && !trx.outputs.is_empty() {
+            return Err(anyhow!("Locked transfers can't have more than one output: {:?}", trx))
+        }
+        if let (Some(after), Some(until)) = (trx.valid_after, trx.valid_until) {
+            if after > until {
+                return Err(anyhow!("Transaction is never valid: {:?}", trx))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unregistered accounts are registered on the fly if the rules allow
+    /// it, in which case the sender pays the registration fee of each
+    /// recipient. The amount of a locked transfer goes to its escrow account
+    /// instead of the recipient.
+    fn apply(&self, accounts: &mut Accounts, trx: &AccountTransaction, funded: bool) -> anyhow::Result<()> {
+        Ledger::check(trx)?;
+        if trx.asset != AssetId::DKK && !accounts.assets.contains_key(&trx.asset) {
+            return Err(anyhow!("{} has not been issued: {:?}", trx.asset, trx))
+        }
+
+        let ids: BTreeSet<Id> = std::iter::once(trx.from).chain(trx.recipients().map(|(to, _)| to)).collect();
+        let mut fee = trx.fee;
+        for id in &ids {
+            if accounts.is_registered(id) {
+                continue
+            }
+            if !self.rules.implicit_registration {
+                return Err(anyhow!("{} is not registered: {:?}", id, trx))
+            }
+            if trx.recipients().any(|(to, _)| to == *id) {
+                fee = fee.checked_add(self.rules.registration_fee).ok_or_else(|| anyhow!("Transaction fee overflows: {:?}", trx))?;
+            }
+        }
+
+        let amount = trx.total_amount().ok_or_else(|| anyhow!("Transaction amounts overflow: {:?}", trx))?;
+        if funded {
+            let dkk = accounts.balance(&trx.from, &AssetId::DKK);
+            let covered = match trx.asset {
+                AssetId::DKK => amount.checked_add(fee).is_some_and(|total| dkk >= total),
+                asset => dkk >= fee && accounts.balance(&trx.from, &asset) >= amount,
+            };
+            if !covered {
+                return Err(anyhow!("{} doesn't have enough to cover {:?}", trx.from, trx))
+            }
+        }
 
-use crate::{*, macros::log_fail};
+        let mut amounts = vec![(trx.from, trx.asset, Amount(0) - amount)];
+        match &trx.lock {
+            Some(_) => amounts.push((trx.escrow_id(), trx.asset, trx.amount)),
+            None => amounts.extend(trx.recipients().map(|(to, amount)| (to, trx.asset, amount))),
+        }
+        if fee != Amount(0) {
+            amounts.push((trx.from, AssetId::DKK, Amount(0) - fee));
+            amounts.push((FEE_POOL, AssetId::DKK, fee));
+        }
+        accounts.add_all(&amounts)?;
 
-#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
-pub struct NodeName(pub String);
+        for id in ids {
+            accounts.registered.entry(id).or_insert(AccountInfo { created: trx.timestamp, label: None });
+        }
+        if let Some(lock) = &trx.lock {
+            let escrow = Escrow { from: trx.from, to: trx.to, asset: trx.asset, amount: trx.amount, lock: lock.clone() };
+            accounts.escrows.insert(trx.escrow_id(), escrow);
+        }
 
-impl fmt::Debug for NodeName {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        Ok(())
     }
-}
-
-#[derive(Eq, PartialEq, Hash, Clone, Copy, Decode, Encode, Debug)]
-pub struct Signature([u8; 64]);
 
-impl From<ed25519_dalek::Signature> for Signature {
-    fn from(signature: ed25519_dalek::Signature) -> Signature {
-        Signature(signature.to_bytes())
+    pub(crate) fn add(&self, id: &Id, amount: Amount) {
+        self.accounts.write().unwrap().add(id, &AssetId::DKK, amount);
     }
-}
 
-impl Into<ed25519_dalek::Signature> for Signature {
-    fn into(self) -> ed25519_dalek::Signature {
-        ed25519_dalek::Signature::from_bytes(&self.0)
+    /// The sum of every DKK balance, including the fee pool.
+    pub fn total_supply(&self) -> Amount {
+        self.snapshot().total_supply()
     }
-}
 
-#[derive(Eq, PartialEq, Clone)]
-pub struct PrivateKey(SigningKey);
+    /// Checks that no money has been created or destroyed, i.e. that the
+    /// total supply is still what it started out as.
+    pub fn check_supply(&self, expected: Amount) -> anyhow::Result<()> {
+        let supply = self.total_supply();
+        if supply != expected {
+            return Err(anyhow!("Total supply is {:?} DKK, expected {:?} DKK", supply, expected))
+        }
 
-impl From<SigningKey> for PrivateKey {
-    fn from(key: SigningKey) -> PrivateKey {
-        PrivateKey(key)
+        Ok(())
     }
-}
 
-impl PrivateKey {
-    pub fn sign(&self, trx: AccountTransaction) -> anyhow::Result<SignedAccountTransaction> {
-        let bytes = bincode::encode_to_vec(&trx, bincode::config::standard())?;
-        let signature = self.0.sign(&bytes);
-        Ok(SignedAccountTransaction {
-            signature: signature.into(),
-            trx
-        })
+    /// The DKK balance of `id`.
+    pub fn get(&self, id: &Id) -> Option<Amount> {
+        self.get_asset(id, &AssetId::DKK)
     }
 
-    pub fn get_pk(&self) -> PublicKey {
-        self.0.verifying_key().into()
+    pub fn get_asset(&self, id: &Id, asset: &AssetId) -> Option<Amount> {
+        self.accounts.read().unwrap().balances.get(&(*id, *asset)).copied()
     }
-}
 
-pub type PublicKey = Id;
+    pub fn state_root(&self) -> Hash {
+        self.snapshot().state_root()
+    }
 
-#[derive(Eq, PartialEq, Clone)]
-pub struct KeyPair {
-    pub public: PublicKey,
-    pub private: PrivateKey,
-}
+    pub fn prove_balance(&self, id: &Id) -> BalanceProof {
+        self.snapshot().prove_balance(id)
+    }
 
-#[derive(Eq, PartialEq, Hash, Clone, Copy, Decode, Encode)]
-pub struct Id([u8; 32]);
+    pub fn prove_asset_balance(&self, id: &Id, asset: &AssetId) -> BalanceProof {
+        self.snapshot().prove_asset_balance(id, asset)
+    }
 
-impl Id {
-    fn verify(&self, msg: &[u8], s: &Signature) -> bool {
-        let signature: ed25519_dalek::Signature = s.clone().into();
-        let res = VerifyingKey::from_bytes(&self.0).unwrap().verify_strict(msg, &signature);
-        match res {
-            Ok(_) => true,
-            Err(_) => false
-        }
+    pub fn diff(&self, other: &Ledger) -> Vec<AccountDiff> {
+        self.snapshot().diff(&other.snapshot())
     }
-}
 
-impl From<VerifyingKey> for Id {
-    fn from(key: VerifyingKey) -> Id {
-        Id(VerifyingKey::to_bytes(&key))
+    fn leaf(id: &Id, asset: &AssetId, amount: &Amount) -> Hash {
+        let mut bytes = id.as_bytes().to_vec();
+        bytes.extend_from_slice(asset.as_bytes());
+        bytes.extend_from_slice(&amount.0.to_le_bytes());
+        Hash::leaf(&bytes)
     }
 }
 
-impl FromStr for Id {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut bytes = [0u8; 32];
-        Base64::decode(s, &mut bytes).unwrap();
-        let key = VerifyingKey::from_bytes(&bytes)?;
+/// Snapshots only hold balances, so every account in it counts as
+/// registered at time zero, no asset counts as issued, escrows can't be
+/// released, and the default rules apply.
+impl From<LedgerSnapshot> for Ledger {
+    fn from(snapshot: LedgerSnapshot) -> Ledger {
+        let registered = snapshot.0
+            .keys()
+            .map(|(id, _)| (*id, AccountInfo { created: Timestamp::from_millis(0), label: None }))
+            .collect();
+        let accounts = Accounts {
+            balances: snapshot.0.into_iter().collect(),
+            registered,
+            ..Default::default()
+        };
 
-        Ok(key.into())
+        Ledger {
+            accounts: Arc::new(RwLock::new(accounts)),
+            rules: AccountRules::default(),
+        }
     }
 }
 
-impl fmt::Display for Id {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let str = Base64::encode_string(&self.0);
-        write!(f, "{:#?}", str)
-    }
-}
+/// The ledger at one point in time, with the balances sorted by account and asset.
+#[derive(Clone, Default, Eq, PartialEq, Hash, Encode, Decode)]
+pub struct LedgerSnapshot(BTreeMap<(Id, AssetId), Amount>);
 
-impl fmt::Debug for Id {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let str = Base64::encode_string(&self.0);
-        write!(f, "{:#?}", str)
+/// JSON keys must be strings, so the balances are nested by account and then asset.
+impl Serialize for LedgerSnapshot {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut accounts: BTreeMap<&Id, BTreeMap<&AssetId, &Amount>> = BTreeMap::new();
+        for ((id, asset), amount) in &self.0 {
+            accounts.entry(id).or_default().insert(asset, amount);
+        }
+        accounts.serialize(serializer)
     }
 }
 
-#[derive(Copy, Eq, PartialEq, Hash, Clone, Encode, Decode)]
-pub struct Amount(pub i64);
-
-impl fmt::Debug for Amount {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+impl<'de> Deserialize<'de> for LedgerSnapshot {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<LedgerSnapshot, D::Error> {
+        let accounts = BTreeMap::<Id, BTreeMap<AssetId, Amount>>::deserialize(deserializer)?;
+        Ok(LedgerSnapshot(accounts
+            .into_iter()
+            .flat_map(|(id, balances)| balances.into_iter().map(move |(asset, amount)| ((id, asset), amount)))
+            .collect()))
     }
 }
 
-impl std::ops::Sub for Amount {
-    type Output = Amount;
-
-    fn sub(self, rhs: Amount) -> Self::Output {
-        Amount(self.0 - rhs.0)
+impl LedgerSnapshot {
+    /// The DKK balance of `id`.
+    pub fn get(&self, id: &Id) -> Option<Amount> {
+        self.get_asset(id, &AssetId::DKK)
     }
-}
 
-impl std::ops::Sub for &Amount {
-    type Output = Amount;
+    pub fn get_asset(&self, id: &Id, asset: &AssetId) -> Option<Amount> {
+        self.0.get(&(*id, *asset)).copied()
+    }
 
-    fn sub(self, rhs: &Amount) -> Self::Output {
-        Amount(self.0 - rhs.0)
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
-}
 
-impl std::ops::Add for Amount {
-    type Output = Amount;
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 
-    fn add(self, rhs: Amount) -> Self::Output {
-        Amount(self.0 + rhs.0)
+    pub fn iter(&self) -> impl Iterator<Item = (&(Id, AssetId), &Amount)> {
+        self.0.iter()
     }
-}
 
-#[derive(Eq, PartialEq, Hash, Clone, Encode, Decode)]
-pub struct Timestamp(u64);
+    /// The sum of every DKK balance.
+    pub fn total_supply(&self) -> Amount {
+        self.asset_supply(&AssetId::DKK)
+    }
 
-impl fmt::Debug for Timestamp {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", Duration::from_millis(self.0).as_secs())?;
+    pub fn asset_supply(&self, asset: &AssetId) -> Amount {
+        Amount(self.0.iter().filter(|((_, a), _)| a == asset).map(|(_, x)| x.0).sum())
+    }
 
-        Ok(())
+    /// A commitment to every balance, a Merkle root over the balances sorted
+    /// by account and asset.
+    pub fn state_root(&self) -> Hash {
+        self.tree().root()
     }
-}
 
-impl Timestamp {
-    pub fn since_unix() -> anyhow::Result<Timestamp> {
-        Ok(Timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64))
+    /// Proves the DKK balance of `id`, or that it has none, against the state root.
+    pub fn prove_balance(&self, id: &Id) -> BalanceProof {
+        self.prove_asset_balance(id, &AssetId::DKK)
     }
-}
 
-#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
-pub struct SignedAccountTransaction {
-    pub signature: Signature,
-    pub trx: AccountTransaction
-}
+    pub fn prove_asset_balance(&self, id: &Id, asset: &AssetId) -> BalanceProof {
+        let balances: Vec<(Id, AssetId, Amount)> = self.0.iter().map(|((id, asset), amount)| (*id, *asset, *amount)).collect();
+        let tree = self.tree();
+        let entry = |i: usize| {
+            let (id, asset, amount) = balances[i];
+            (id, asset, amount, tree.prove(i).unwrap())
+        };
 
-impl fmt::Debug for SignedAccountTransaction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "󰱒 {:?}", self.trx)
+        let inclusion = match balances.binary_search_by(|(x, a, _)| (x, a).cmp(&(id, asset))) {
+            Ok(i) => Inclusion::Present(entry(i)),
+            Err(i) => Inclusion::Absent {
+                left: i.checked_sub(1).map(entry),
+                right: (i < balances.len()).then(|| entry(i)),
+            },
+        };
+
+        BalanceProof {
+            state_root: tree.root(),
+            inclusion,
+        }
     }
-}
 
-impl SignedAccountTransaction {
-    pub fn verify(&self) -> bool {
-        let bytes_res = bincode::encode_to_vec(&self.trx, bincode::config::standard());
-        let bytes = match bytes_res {
-            Ok(bs) => bs,
-            Err(_) => return false,
-        };
-        self.trx.from.verify(&bytes, &self.signature)
+    /// Every balance that differs between the two, sorted by account and asset.
+    pub fn diff(&self, other: &LedgerSnapshot) -> Vec<AccountDiff> {
+        let keys: BTreeSet<&(Id, AssetId)> = self.0.keys().chain(other.0.keys()).collect();
+
+        keys.into_iter()
+            .map(|(id, asset)| AccountDiff {
+                id: *id,
+                asset: *asset,
+                ours: self.get_asset(id, asset),
+                theirs: other.get_asset(id, asset),
+            })
+            .filter(|diff| diff.ours != diff.theirs)
+            .collect()
     }
-}
 
-#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode)]
-pub struct AccountTransaction {
-    pub to: Id,
-    pub from: Id,
-    pub amount: Amount,
-    pub timestamp: Timestamp
+    fn tree(&self) -> MerkleTree {
+        MerkleTree::new(self.0.iter().map(|((id, asset), amount)| Ledger::leaf(id, asset, amount)).collect())
+    }
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Decode, Encode, Debug)]
-pub struct AccountCreation {
+/// The balance of an account in two ledgers, `None` if it isn't in one of them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountDiff {
     pub id: Id,
+    pub asset: AssetId,
+    pub ours: Option<Amount>,
+    pub theirs: Option<Amount>,
 }
 
-impl fmt::Display for AccountTransaction {
+impl fmt::Debug for LedgerSnapshot {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?} -> {:?}: {:?} DKK", self.from, self.to, self.amount)
-    }
-}
+        write!(f, "[ ")?;
+        for ((id, asset), amount) in self.iter() {
+            write!(f, "({:?}, {:?} {}) ", id, amount, asset)?
+        }
+        write!(f, "]")?;
 
-impl fmt::Debug for AccountTransaction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?} -> {:?}: {:?} DKK", self.from, self.to, self.amount)
+        Ok(())
     }
 }
 
-pub type NodeRequest = (Packet, Peer);
-
 #[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
-pub enum Packet {
-    GetPeers,
-    AddPeer(SocketAddr),
-    Broadcast(SignedAccountTransaction),
-    ResponseGetPeers(Vec<SocketAddr>),
+pub enum Inclusion {
+    Present((Id, AssetId, Amount, MerkleProof)),
+    /// The neighbours the balance would have been between, had it existed.
+    Absent {
+        left: Option<(Id, AssetId, Amount, MerkleProof)>,
+        right: Option<(Id, AssetId, Amount, MerkleProof)>,
+    },
 }
 
-#[derive(Eq, PartialEq, Clone, Hash, Encode, Decode)]
-pub enum RpcCall {
-    GetPeers,
+/// A proof of the balance of a single account against a state root.
+#[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
+pub struct BalanceProof {
+    pub state_root: Hash,
+    pub inclusion: Inclusion,
 }
 
-#[derive(Clone)]
-pub struct Ledger(Arc<DashMap<Id, Amount>>);
-
-impl Ledger {
-    pub fn new() -> Ledger {
-        Ledger(Arc::new(DashMap::new()))
-    }
-
-    pub fn len(&self) -> usize {
-        self.0.len()
+impl BalanceProof {
+    /// Returns the proven DKK balance of `id` if the proof holds against the
+    /// trusted `root`. Accounts not in the ledger have a balance of 0.
+    pub fn verify(&self, root: &Hash, id: &Id) -> Option<Amount> {
+        self.verify_asset(root, id, &AssetId::DKK)
     }
 
-    pub fn update(&self, trx: &AccountTransaction) -> anyhow::Result<()> {
-        let from_amount = self.0
-            .get(&trx.from)
-            .map(|x| x.value().clone())
-            .unwrap_or(Amount(0));
-        let to_amount = self.0
-            .get(&trx.to)
-            .map(|x| x.value().clone())
-            .unwrap_or(Amount(0));
-
-        self.0.insert(trx.from.clone(), from_amount - trx.amount);
-        self.0.insert(trx.to.clone(), to_amount + trx.amount);
-
-        Ok(())
-    }
+    pub fn verify_asset(&self, root: &Hash, id: &Id, asset: &AssetId) -> Option<Amount> {
+        if self.state_root != *root {
+            return None
+        }
 
-    pub fn get(&self, id: &Id) -> Option<Amount> {
-        self.0.get(id).map(|x| x.clone())
+        let verify_entry = |(entry_id, entry_asset, amount, proof): &(Id, AssetId, Amount, MerkleProof)| {
+            proof.verify(root, &Ledger::leaf(entry_id, entry_asset, amount))
+        };
+        let key = (id, asset);
+
+        match &self.inclusion {
+            Inclusion::Present(entry) => {
+                ((&entry.0, &entry.1) == key && verify_entry(entry)).then_some(entry.2)
+            }
+            Inclusion::Absent { left, right } => {
+                let left_ok = left.as_ref().is_none_or(|l| (&l.0, &l.1) < key && verify_entry(l));
+                let right_ok = right.as_ref().is_none_or(|r| key < (&r.0, &r.1) && verify_entry(r));
+                // The neighbours must be adjacent in the same tree, or the first/last leaf when one
+                // is missing. A proof can verify against a different leaf count than the real one,
+                // so neighbours claiming different counts could skip over a leaf.
+                let adjacent = match (left, right) {
+                    (Some(l), Some(r)) => l.3.leaf_count == r.3.leaf_count && l.3.index + 1 == r.3.index,
+                    (Some(l), None) => l.3.index + 1 == l.3.leaf_count,
+                    (None, Some(r)) => r.3.index == 0,
+                    (None, None) => *root == Hash::default(),
+                };
+
+                (left_ok && right_ok && adjacent).then_some(Amount(0))
+            }
+        }
     }
 }
 
 impl fmt::Debug for Ledger {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[ ")?;
-        for i in self.0.iter() {
-            write!(f, "({:?}, {:?}) ", i.key(), i.value())?
-        }
-        write!(f, "]")?;
-
-        Ok(())
+        fmt::Debug::fmt(&self.snapshot(), f)
     }
 }
 
 impl PartialEq for Ledger {
     fn eq(&self, other: &Self) -> bool {
-        self.len() == other.len() &&
-        self.0.iter().all(|p| other.0.get(p.key()).is_some_and(|q| q.value() == p.value()))
+        self.snapshot() == other.snapshot()
     }
 }
 
@@ -282,15 +1501,18 @@ impl Eq for Ledger {}
 #[derive(Clone)]
 pub struct Peers {
     node_name: NodeName,
+    /// Sent to every new connection. Peers with an incompatible handshake are refused.
+    handshake: Handshake,
     active: Arc<DashMap<SocketAddr, Peer>>,
     inactive: Arc<DashSet<SocketAddr>>,
     self_address: SocketAddr
 }
 
 impl Peers {
-    pub fn new(self_address: SocketAddr, node_name: NodeName) -> Peers {
+    pub fn new(self_address: SocketAddr, node_name: NodeName, handshake: Handshake) -> Peers {
         Peers {
             node_name,
+            handshake,
             active: Arc::new(DashMap::new()),
             inactive: Arc::new(DashSet::new()),
             self_address
@@ -301,6 +1523,11 @@ impl Peers {
         self.active.len() + self.inactive.len() + 1
     }
 
+    /// Never, as we count ourselves.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
     pub fn clone_iter(&self) -> dashmap::iter::OwningIter<SocketAddr, Peer> {
         (*self.active).clone().into_iter()
     }
@@ -309,6 +1536,10 @@ impl Peers {
         self.active.iter()
     }
 
+    pub fn get(&self, key: &SocketAddr) -> Option<Peer> {
+        self.active.get(key).map(|p| p.value().clone())
+    }
+
     pub fn contains(&self, key: &SocketAddr) -> bool {
         self.active.contains_key(key) || self.inactive.contains(key) || self.self_address == *key
     }
@@ -321,9 +1552,9 @@ impl Peers {
         }
         let mut peers: Vec<SocketAddr> = self.active
             .iter()
-            .map(|x| x.key().clone())
-            .chain(self.inactive.iter().map(|x| x.key().clone()))
-            .chain(self_address.into_iter())
+            .map(|x| *x.key())
+            .chain(self.inactive.iter().map(|x| *x.key()))
+            .chain(self_address)
             .collect();
 
         // Randomize peers
@@ -352,7 +1583,7 @@ impl Peers {
 
         if !(self.contains(&address)) {
             let stream = TcpStream::connect(address).await?;
-            let peer = Peer::new(node_tx, stream, self.node_name.clone()).await?;
+            let peer = Peer::new(node_tx, stream, self.node_name.clone(), &self.handshake).await?;
             log_fail!(self.add_peer(address, peer.clone()));
             peer.send(Packet::GetPeers).await;
         }
@@ -361,7 +1592,7 @@ impl Peers {
     }
 
     pub async fn new_stream(&self, node_tx: Sender<NodeRequest>, stream: TcpStream) -> anyhow::Result<()> {
-        let peer = Peer::new(node_tx, stream, self.node_name.clone()).await?;
+        let peer = Peer::new(node_tx, stream, self.node_name.clone(), &self.handshake).await?;
         peer.send(Packet::GetPeers).await;
 
         Ok(())
@@ -407,21 +1638,267 @@ impl fmt::Debug for Peers {
     }
 }
 
-pub type History = Arc<DashSet<AccountTransaction>>;
+/// Every transaction seen, indexed by the accounts involved in them.
+#[derive(Clone, Default)]
+pub struct History {
+    trxs: Arc<DashSet<AccountTransaction>>,
+    /// The transactions sending from or to each account, ordered by
+    /// timestamp, with ties broken by id.
+    by_account: Arc<DashMap<Id, Vec<AccountTransaction>>>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum Direction {
+    Sent,
+    Received,
+    #[default]
+    Both,
+}
+
+/// Selects a page of the transactions of an account. Time bounds are inclusive.
+#[derive(Clone, Debug)]
+pub struct HistoryFilter {
+    pub direction: Direction,
+    pub after: Option<Timestamp>,
+    pub before: Option<Timestamp>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Default for HistoryFilter {
+    fn default() -> HistoryFilter {
+        HistoryFilter {
+            direction: Direction::Both,
+            after: None,
+            before: None,
+            offset: 0,
+            limit: usize::MAX,
+        }
+    }
+}
+
+impl HistoryFilter {
+    fn matches(&self, id: &Id, trx: &AccountTransaction) -> bool {
+        let direction = match self.direction {
+            Direction::Sent => trx.from == *id,
+            Direction::Received => trx.recipients().any(|(to, _)| to == *id),
+            Direction::Both => true,
+        };
+
+        direction
+            && self.after.as_ref().is_none_or(|after| trx.timestamp >= *after)
+            && self.before.as_ref().is_none_or(|before| trx.timestamp <= *before)
+    }
+}
+
+impl History {
+    /// Returns false if the transaction was already in the history.
+    pub fn insert(&self, trx: AccountTransaction) -> bool {
+        if !self.trxs.insert(trx.clone()) {
+            return false
+        }
+
+        for id in Self::accounts(&trx) {
+            let mut trxs = self.by_account.entry(id).or_default();
+            let key = (trx.timestamp, trx.id());
+            let i = trxs.partition_point(|t| (t.timestamp, t.id()) <= key);
+            trxs.insert(i, trx.clone());
+        }
+
+        true
+    }
+
+    /// Undoes `insert`, for a transaction the ledger then refused or that expired.
+    pub fn remove(&self, trx: &AccountTransaction) -> bool {
+        if self.trxs.remove(trx).is_none() {
+            return false
+        }
+
+        for id in Self::accounts(trx) {
+            self.by_account.alter(&id, |_, mut trxs| {
+                trxs.retain(|t| t != trx);
+                trxs
+            });
+            self.by_account.remove_if(&id, |_, trxs| trxs.is_empty());
+        }
+
+        true
+    }
+
+    /// Removes the transactions that expired before `before`, returning how many.
+    pub fn evict_expired(&self, before: Timestamp) -> usize {
+        let expired: Vec<AccountTransaction> = self.trxs
+            .iter()
+            .filter(|trx| trx.valid_until.is_some_and(|until| until < before))
+            .map(|trx| trx.clone())
+            .collect();
+
+        expired.iter().filter(|trx| self.remove(trx)).count()
+    }
+
+    fn accounts(trx: &AccountTransaction) -> Vec<Id> {
+        let mut accounts: Vec<Id> = std::iter::once(trx.from).chain(trx.recipients().map(|(to, _)| to)).collect();
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+
+    pub fn contains(&self, trx: &AccountTransaction) -> bool {
+        self.trxs.contains(trx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.trxs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trxs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = dashmap::setref::multiple::RefMulti<'_, AccountTransaction>> {
+        self.trxs.iter()
+    }
+
+    /// Every transaction, oldest first, with ties broken by id.
+    pub fn ordered(&self) -> Vec<AccountTransaction> {
+        let mut trxs: Vec<AccountTransaction> = self.trxs.iter().map(|trx| trx.clone()).collect();
+        trxs.sort_by_cached_key(|trx| (trx.timestamp, trx.id()));
+        trxs
+    }
+
+    /// The transactions of `id` matching `filter`, oldest first.
+    pub fn query(&self, id: &Id, filter: &HistoryFilter) -> Vec<AccountTransaction> {
+        let trxs = match self.by_account.get(id) {
+            Some(trxs) => trxs,
+            None => return vec![],
+        };
+
+        trxs.iter()
+            .filter(|t| filter.matches(id, t))
+            .skip(filter.offset)
+            .take(filter.limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Like `query`, but with the balance of `id` in the asset of each
+    /// transaction right after it. The balances are worked out backwards,
+    /// in timestamp order, from `balance`, the current balance of an asset.
+    /// Releases and registrations aren't in the history, so balances before
+    /// them are off by their amounts. `None` if `balance` is, or it overflows.
+    pub fn query_balances(
+        &self,
+        id: &Id,
+        filter: &HistoryFilter,
+        balance: impl Fn(&AssetId) -> Option<Amount>,
+    ) -> Vec<(AccountTransaction, Option<Amount>)> {
+        let trxs = match self.by_account.get(id) {
+            Some(trxs) => trxs.clone(),
+            None => return vec![],
+        };
+
+        let mut balances: HashMap<AssetId, Option<Amount>> = HashMap::new();
+        let mut with_balances: Vec<(AccountTransaction, Option<Amount>)> = trxs
+            .into_iter()
+            .rev()
+            .map(|trx| {
+                let after = *balances.entry(trx.asset).or_insert_with(|| balance(&trx.asset));
+                for (asset, change) in Self::changes(id, &trx) {
+                    let before = balances.entry(asset).or_insert_with(|| balance(&asset));
+                    *before = before.and_then(|b| b.checked_sub(change));
+                }
+                (trx, after)
+            })
+            .collect();
+        with_balances.reverse();
+
+        with_balances
+            .into_iter()
+            .filter(|(t, _)| filter.matches(id, t))
+            .skip(filter.offset)
+            .take(filter.limit)
+            .collect()
+    }
+
+    /// How much `trx` changes the balances of `id`, by asset. Locked amounts
+    /// go to the escrow, so they don't reach the recipient.
+    fn changes(id: &Id, trx: &AccountTransaction) -> Vec<(AssetId, Amount)> {
+        let mut changes = vec![];
+        if trx.from == *id {
+            changes.push((trx.asset, Amount(0) - trx.total_amount().unwrap_or_default()));
+            changes.push((AssetId::DKK, Amount(0) - trx.fee));
+        }
+        if trx.lock.is_none() {
+            changes.extend(trx.recipients().filter(|(to, _)| to == id).map(|(_, amount)| (trx.asset, amount)));
+        }
+        changes
+    }
+
+    /// A Merkle root over the ids of every transaction in the history, in
+    /// order of id. Two nodes have seen the same transactions iff their roots agree.
+    pub fn root(&self) -> Hash {
+        history_tree(&self.sorted_ids()).root()
+    }
+
+    /// Proves that the transaction `id` is part of the history.
+    pub fn prove_tx(&self, id: &TxId) -> Option<TxProof> {
+        let ids = self.sorted_ids();
+        let tree = history_tree(&ids);
+        let i = ids.binary_search_by(|x| x.0.cmp(&id.0)).ok()?;
+
+        Some(TxProof {
+            history_root: tree.root(),
+            proof: tree.prove(i)?,
+        })
+    }
+
+    fn sorted_ids(&self) -> Vec<TxId> {
+        let mut ids: Vec<TxId> = self.trxs.iter().map(|trx| trx.id()).collect();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
+}
+
+impl fmt::Debug for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.trxs.iter().map(|t| t.key().clone())).finish()
+    }
+}
+
+fn history_tree(ids: &[TxId]) -> MerkleTree {
+    MerkleTree::new(ids.iter().map(|id| Hash::leaf(&id.0)).collect())
+}
+
+/// A proof that a transaction is included in a history root.
+#[derive(Eq, PartialEq, Clone, Hash, Decode, Encode, Debug)]
+pub struct TxProof {
+    pub history_root: Hash,
+    pub proof: MerkleProof,
+}
+
+impl TxProof {
+    pub fn verify(&self, root: &Hash, id: &TxId) -> bool {
+        self.history_root == *root && self.proof.verify(root, &Hash::leaf(&id.0))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct State {
     pub history: History,
     pub ledger: Ledger,
-    pub peers: Peers
+    pub peers: Peers,
+    /// Transactions whose signatures have already been verified, with when
+    /// they are evicted.
+    pub verified: Arc<DashMap<TxId, Timestamp>>,
 }
 
 impl State {
-    pub fn new(self_socket: SocketAddr, node_name: NodeName) -> State {
+    pub fn new(self_socket: SocketAddr, node_name: NodeName, genesis: &Genesis, features: Features) -> State {
         State {
-            history: Arc::new(DashSet::new()),
-            ledger: Ledger::new(),
-            peers: Peers::new(self_socket, node_name)
+            verified: Arc::new(DashMap::new()),
+            history: History::default(),
+            ledger: Ledger::from_genesis(genesis),
+            peers: Peers::new(self_socket, node_name, Handshake::new(genesis.id(), features))
         }
     }
 }